@@ -15,6 +15,13 @@ ShellRoot {
     property bool responseServerActive: false
     property string userUid: "1000"
     property bool uidReady: false
+    property string socketDir: {
+        const runtimeDir = Quickshell.env("XDG_RUNTIME_DIR")
+        return runtimeDir ? runtimeDir + "/qs-daemon" : "/tmp/qs-daemon-" + userUid
+    }
+    // Each picker listens on its own response socket so concurrent pickers
+    // never receive each other's results.
+    readonly property string instanceId: Date.now().toString(36)
 
     onUidReadyChanged: {
         if (uidReady && window) {
@@ -44,6 +51,12 @@ ShellRoot {
         }
     }
 
+    function openFile(path) {
+        requestSocket.sendRecordSelection(path)
+        Quickshell.execDetached(["xdg-open", path])
+        Qt.quit()
+    }
+
     function getHighlightedSegments(text, matches) {
         if (!text) return [{ text: "", highlighted: false, part: 'file' }];
 
@@ -149,7 +162,7 @@ ShellRoot {
 
         SocketServer {
             id: responseServer
-            path: "/run/user/" + userUid + "/quickfile-response.sock"
+            path: socketDir + "/response-" + instanceId + ".sock"
 
             onActiveChanged: {
                 responseServerActive = active
@@ -183,17 +196,32 @@ ShellRoot {
 
         Socket {
             id: requestSocket
-            path: "/run/user/" + userUid + "/quickfile-daemon.sock"
+            path: socketDir + "/request.sock"
 
             onConnectedChanged: {
                 if (connected) {
                     daemonConnected = true
+                    sendSubscribe()
                     sendSearchRequest("")
                 } else {
                     daemonConnected = false
                 }
             }
 
+            function sendSubscribe() {
+                write(JSON.stringify({ "type": "Subscribe", "response_socket": responseServer.path }) + "\n")
+                flush()
+            }
+
+            function sendRecordSelection(path) {
+                if (!connected) {
+                    return
+                }
+
+                write(JSON.stringify({ "type": "RecordSelection", "path": path }) + "\n")
+                flush()
+            }
+
             function sendSearchRequest(query) {
                 if (!connected) {
                     return
@@ -279,8 +307,7 @@ ShellRoot {
 
                         Keys.onReturnPressed: {
                             if (filteredFiles.length > 0 && selectedIndex < filteredFiles.length) {
-                                Quickshell.execDetached(["xdg-open", filteredFiles[selectedIndex].path])
-                                Qt.quit()
+                                openFile(filteredFiles[selectedIndex].path)
                             }
                         }
 
@@ -342,8 +369,7 @@ ShellRoot {
                             }
 
                             onClicked: {
-                                Quickshell.execDetached(["xdg-open", modelData.path])
-                                Qt.quit()
+                                openFile(modelData.path)
                             }
                         }
                     }
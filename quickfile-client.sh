@@ -7,8 +7,9 @@
 #        quickfile-client refresh
 #        quickfile-client status
 
-REQUEST_SOCKET="/tmp/quickfile-daemon.sock"
-RESPONSE_SOCKET="/tmp/quickfile-response.sock" 
+SOCKET_DIR="${XDG_RUNTIME_DIR:+$XDG_RUNTIME_DIR/qs-daemon}"
+SOCKET_DIR="${SOCKET_DIR:-/tmp/qs-daemon-$(id -u)}"
+REQUEST_SOCKET="${QS_REQUEST_SOCKET:-$SOCKET_DIR/request.sock}"
 RESPONSE_TIMEOUT=5
 
 # Check if request socket exists
@@ -17,44 +18,9 @@ if [ ! -S "$REQUEST_SOCKET" ]; then
     exit 1
 fi
 
-# Function to send request and get response using 2-socket method
-send_request_2socket() {
-    local request="$1"
-    local response_file=$(mktemp)
-    local response_pid
-    
-    # Try to listen on response socket in background
-    if [ -S "$RESPONSE_SOCKET" ]; then
-        socat UNIX-CONNECT:$RESPONSE_SOCKET STDOUT > "$response_file" &
-        response_pid=$!
-        
-        # Give a moment for response listener to connect
-        sleep 0.1
-        
-        # Send request
-        echo "$request" | socat - UNIX-CONNECT:$REQUEST_SOCKET
-        
-        # Wait for response with timeout
-        local count=0
-        while [ $count -lt $((RESPONSE_TIMEOUT * 10)) ] && kill -0 $response_pid 2>/dev/null; do
-            if [ -s "$response_file" ]; then
-                cat "$response_file"
-                kill $response_pid 2>/dev/null
-                rm -f "$response_file"
-                return 0
-            fi
-            sleep 0.1
-            count=$((count + 1))
-        done
-        
-        # Cleanup if timeout or error
-        kill $response_pid 2>/dev/null
-        rm -f "$response_file"
-    fi
-    
-    # Fallback to single socket method
-    echo "$request" | socat - UNIX-CONNECT:$REQUEST_SOCKET
-    rm -f "$response_file"
+# Without a Subscribe request the daemon answers on the request connection
+send_request() {
+    echo "$1" | socat -t "$RESPONSE_TIMEOUT" - UNIX-CONNECT:$REQUEST_SOCKET
 }
 
 # Build request based on command
@@ -76,4 +42,4 @@ case "$1" in
 esac
 
 # Send request and display response
-send_request_2socket "$REQUEST"
+send_request "$REQUEST"
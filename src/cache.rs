@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::scanner::ScanConfig;
+use crate::{EntryType, FileEntry, FileMetadata};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout below changes.
+const CACHE_VERSION: u32 = 9;
+
+/// Scan settings the cached list was built with. A cache written with
+/// different settings is ignored rather than served.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CacheKey {
+    scanner: String,
+    roots: Vec<PathBuf>,
+    exclude: Vec<String>,
+    include: Vec<String>,
+    include_dirs: bool,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    collect_metadata: bool,
+    aliases: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    fn new(scan: &ScanConfig) -> Self {
+        Self {
+            scanner: scan.scanner.name().to_string(),
+            roots: scan.roots.clone(),
+            exclude: scan.options.exclude.patterns().to_vec(),
+            include: scan.options.include.patterns().to_vec(),
+            include_dirs: scan.options.include_dirs,
+            include_hidden: scan.options.include_hidden,
+            follow_symlinks: scan.options.follow_symlinks,
+            collect_metadata: scan.collect_metadata,
+            aliases: scan.aliases.pairs().to_vec(),
+        }
+    }
+}
+
+// bincode is not self-describing, so entries are stored as plain structs
+// without the flatten/skip attributes used on the wire.
+#[derive(Serialize, Deserialize)]
+struct CachedFile<'a> {
+    /// Stored rather than recomputed, which would resolve every path.
+    id: u64,
+    path: Cow<'a, str>,
+    display_path: Cow<'a, str>,
+    entry_type: EntryType,
+    size: Option<u64>,
+    modified: Option<u64>,
+    is_symlink: Option<bool>,
+    inode: Option<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexCache<'a> {
+    version: u32,
+    key: CacheKey,
+    last_updated: u64,
+    /// Whether `max_files` had left files out of the list.
+    capped: bool,
+    files: Vec<CachedFile<'a>>,
+}
+
+/// Default location: `$XDG_CACHE_HOME/qs-daemon/index.bin`.
+pub fn default_path() -> PathBuf {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(crate::home_dir()).join(".cache"));
+    cache_home.join("qs-daemon").join("index.bin")
+}
+
+pub fn save(
+    cache_file: &Path,
+    scan: &ScanConfig,
+    last_updated: SystemTime,
+    files: &[FileEntry],
+    capped: bool,
+) -> Result<()> {
+    let cache = IndexCache {
+        version: CACHE_VERSION,
+        key: CacheKey::new(scan),
+        last_updated: last_updated
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        capped,
+        files: files
+            .iter()
+            .map(|file| CachedFile {
+                id: file.id,
+                path: Cow::Borrowed(&file.path),
+                display_path: Cow::Borrowed(&file.display_path),
+                entry_type: file.entry_type,
+                size: file.metadata.size,
+                modified: file.metadata.modified,
+                is_symlink: file.metadata.is_symlink,
+                inode: file.metadata.inode,
+            })
+            .collect(),
+    };
+    let bytes = bincode::serde::encode_to_vec(&cache, bincode::config::standard())?;
+
+    if let Some(dir) = cache_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = cache_file.with_extension("bin.tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, cache_file)?;
+    Ok(())
+}
+
+/// Returns the cached file list, its timestamp and whether `max_files` had
+/// left files out, or `None` when there is no cache or it was built with
+/// different settings.
+pub fn load(
+    cache_file: &Path,
+    scan: &ScanConfig,
+) -> Result<Option<(SystemTime, Vec<FileEntry>, bool)>> {
+    let bytes = match std::fs::read(cache_file) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (cache, _): (IndexCache, _) =
+        bincode::serde::decode_from_slice(&bytes, bincode::config::standard())?;
+
+    if cache.version != CACHE_VERSION || cache.key != CacheKey::new(scan) {
+        return Ok(None);
+    }
+
+    let files = cache
+        .files
+        .into_iter()
+        .map(|file| FileEntry {
+            id: file.id,
+            path: file.path.into_owned(),
+            display_path: file.display_path.into_owned(),
+            entry_type: file.entry_type,
+            metadata: FileMetadata {
+                size: file.size,
+                modified: file.modified,
+                is_symlink: file.is_symlink,
+                inode: file.inode,
+            },
+        })
+        .collect();
+    let last_updated = UNIX_EPOCH + Duration::from_secs(cache.last_updated);
+    Ok(Some((last_updated, files, cache.capped)))
+}
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use anyhow::{Context, Result};
+use qs_daemon::MatchMode;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// Settings read from `config.toml`. Command-line flags and environment
+/// variables take precedence over anything set here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub roots: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    /// When set, only files matching one of these globs are indexed.
+    pub include: Vec<String>,
+    pub refresh_secs: Option<u64>,
+    pub request_socket: Option<PathBuf>,
+    pub response_socket: Option<PathBuf>,
+    /// Match mode for searches that do not specify one.
+    pub match_mode: MatchMode,
+    /// Weight of the file name in `Weighted` match mode.
+    pub filename_weight: Option<f32>,
+    /// Weight of the directory in `Weighted` match mode.
+    pub dir_weight: Option<f32>,
+    /// Points taken off a fuzzy score per directory level of the path.
+    pub depth_penalty: Option<u32>,
+    /// Short names shown in place of directory prefixes, by prefix.
+    pub path_aliases: BTreeMap<String, String>,
+    /// Long-running command printing NUL-terminated paths to index.
+    pub stream_command: Option<String>,
+    /// Points added to (or, when negative, taken off) fuzzy scores by file
+    /// extension.
+    pub extension_boosts: BTreeMap<String, i32>,
+    /// Points added to a fuzzy score per matched character that starts a
+    /// word.
+    pub boundary_bonus: Option<u32>,
+    /// Extra named indexes served next to the default one.
+    pub namespaces: BTreeMap<String, NamespaceConfig>,
+}
+
+/// One `[namespaces.<name>]` table. Options left out fall back to the
+/// default index's.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceConfig {
+    pub roots: Vec<PathBuf>,
+    pub exclude: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    pub refresh_secs: Option<u64>,
+}
+
+impl Config {
+    /// Default location: `$XDG_CONFIG_HOME/qs-daemon/config.toml`.
+    pub fn default_path() -> PathBuf {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(qs_daemon::home_dir()).join(".config"));
+        config_home.join("qs-daemon").join("config.toml")
+    }
+
+    /// Reads the config file, falling back to defaults when it is missing
+    /// or cannot be parsed.
+    pub fn load(file: &Path) -> Self {
+        match Self::read(file) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{:#}, using defaults", e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Reads the config file, which may be missing but not invalid.
+    pub fn read(file: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read config {}", file.display()));
+            }
+        };
+        let config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config {}", file.display()))?;
+        info!("Loaded config from {}", file.display());
+        Ok(config)
+    }
+}
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use qs_daemon::ConnectionInfo;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+/// Every open connection with its counters, for spotting a client that
+/// floods the daemon.
+#[derive(Debug, Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<ConnectionStats>>>,
+}
+
+/// Counters of one connection, updated by its handler.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    id: u64,
+    peer: String,
+    connected: Instant,
+    requests: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Connections {
+    /// Registers a new connection, listed until it is closed.
+    pub fn open(&self, peer: String) -> Arc<ConnectionStats> {
+        let stats = Arc::new(ConnectionStats {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            peer,
+            connected: Instant::now(),
+            requests: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        });
+        self.lock().insert(stats.id, Arc::clone(&stats));
+        stats
+    }
+
+    pub fn close(&self, stats: &ConnectionStats) {
+        self.lock().remove(&stats.id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.lock()
+            .values()
+            .map(|stats| ConnectionInfo {
+                id: stats.id,
+                peer: stats.peer.clone(),
+                connected_secs: stats.connected.elapsed().as_secs(),
+                requests: stats.requests.load(Ordering::Relaxed),
+                bytes_written: stats.bytes_written.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Arc<ConnectionStats>>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ConnectionStats {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counts a request and returns its number on this connection,
+    /// starting at 1.
+    pub fn record_request(&self) -> u64 {
+        self.requests.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn record_written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Write};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// First byte of a [`Framing::MessagePack`] frame.
+const MESSAGE_PACK_MARKER: u8 = 0x01;
+
+/// First byte of a response payload sent as is, once the client has asked
+/// for compression.
+pub const PLAIN_FLAG: u8 = 0x00;
+
+/// First byte of a response payload that is gzip-compressed.
+pub const GZIP_FLAG: u8 = 0x01;
+
+/// Largest request limit that keeps the first byte of a length-prefixed
+/// request zero.
+pub const MAX_REQUEST_LIMIT: usize = (1 << 24) - 1;
+
+/// A request longer than the configured limit. The rest of it is left
+/// unread, so the connection cannot carry further requests.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub framing: Framing,
+    pub limit: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request exceeds the {}-byte limit", self.limit)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// How a message is delimited and encoded on the wire. Requests pick their
+/// framing by their first byte, and the daemon frames each response the way
+/// the request it answers was framed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// JSON followed by `\n`, as sent by the GUI and shell client.
+    Lines,
+    /// A 4-byte big-endian length followed by that many bytes of JSON.
+    /// Requests are limited to 16 MiB, so their first byte is always zero,
+    /// which no JSON text starts with.
+    LengthPrefixed,
+    /// A `0x01` byte, a 4-byte big-endian length and that many bytes of
+    /// MessagePack, for programmatic clients that want to skip JSON. Maps
+    /// use the same field names as the JSON messages.
+    MessagePack,
+}
+
+impl Framing {
+    /// Serializes a message in the encoding this framing carries.
+    pub fn serialize<T: Serialize>(self, message: &T) -> io::Result<Vec<u8>> {
+        match self {
+            Framing::Lines | Framing::LengthPrefixed => Ok(serde_json::to_vec(message)?),
+            Framing::MessagePack => rmp_serde::to_vec_named(message).map_err(io::Error::other),
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, payload: &[u8]) -> anyhow::Result<T> {
+        match self {
+            Framing::Lines | Framing::LengthPrefixed => Ok(serde_json::from_slice(payload)?),
+            Framing::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+        }
+    }
+
+    /// A serialized message in a form fit for the debug log.
+    pub fn display(self, payload: &[u8]) -> Cow<'_, str> {
+        match self {
+            Framing::Lines | Framing::LengthPrefixed => String::from_utf8_lossy(payload),
+            Framing::MessagePack => Cow::Owned(format!("<{} bytes of MessagePack>", payload.len())),
+        }
+    }
+
+    /// Whether the framing can carry binary payloads, and so compressed
+    /// responses. A line of gzip could contain a stray `\n`.
+    pub fn carries_binary(self) -> bool {
+        !matches!(self, Framing::Lines)
+    }
+
+    /// Wraps a serialized message for sending.
+    pub fn encode(self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            Framing::Lines => {
+                let mut frame = Vec::with_capacity(payload.len() + 1);
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+                frame
+            }
+            Framing::LengthPrefixed => {
+                let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+                let mut frame = Vec::with_capacity(payload.len() + 4);
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(payload);
+                frame
+            }
+            Framing::MessagePack => {
+                let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+                let mut frame = Vec::with_capacity(payload.len() + 5);
+                frame.push(MESSAGE_PACK_MARKER);
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(payload);
+                frame
+            }
+        }
+    }
+}
+
+/// Reads the next message and the framing it was sent with. Returns `None`
+/// once the peer has closed the connection between messages. A message
+/// longer than `limit` bytes fails with a [`FrameTooLarge`] error before
+/// more than `limit` bytes of it are buffered.
+pub async fn read_frame<R>(reader: &mut R, limit: usize) -> io::Result<Option<(Framing, Vec<u8>)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let too_large =
+        |framing| io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge { framing, limit });
+    let first = match reader.fill_buf().await?.first() {
+        Some(&byte) => byte,
+        None => return Ok(None),
+    };
+
+    if first == 0 {
+        let len = reader.read_u32().await? as usize;
+        if len > limit {
+            return Err(too_large(Framing::LengthPrefixed));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        return Ok(Some((Framing::LengthPrefixed, payload)));
+    }
+
+    if first == MESSAGE_PACK_MARKER {
+        reader.read_u8().await?;
+        let len = reader.read_u32().await? as usize;
+        if len > limit {
+            return Err(too_large(Framing::MessagePack));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        return Ok(Some((Framing::MessagePack, payload)));
+    }
+
+    // Room for the longest allowed line and its `\r\n`.
+    let mut line = Vec::new();
+    let max_line = limit as u64 + 2;
+    (&mut *reader)
+        .take(max_line)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    if line.len() > limit {
+        return Err(too_large(Framing::Lines));
+    }
+    Ok(Some((Framing::Lines, line)))
+}
+
+/// Prefixes a serialized response with a flag byte for a client that asked
+/// for compression, gzipping it first when it is longer than `min_bytes`.
+pub fn compress(payload: &[u8], min_bytes: usize) -> io::Result<Vec<u8>> {
+    if payload.len() <= min_bytes {
+        let mut body = Vec::with_capacity(payload.len() + 1);
+        body.push(PLAIN_FLAG);
+        body.extend_from_slice(payload);
+        return Ok(body);
+    }
+    let mut encoder = GzEncoder::new(vec![GZIP_FLAG], flate2::Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn small_payloads_are_flagged_plain() {
+        assert_eq!(compress(b"{}", 16).unwrap(), b"\x00{}");
+    }
+
+    #[test]
+    fn large_payloads_are_gzipped() {
+        let payload = br#"{"path":"/home/user/src/main.rs"}"#.repeat(100);
+        let body = compress(&payload, 16).unwrap();
+        assert_eq!(body[0], GZIP_FLAG);
+        assert!(body.len() < payload.len());
+        let mut inflated = Vec::new();
+        GzDecoder::new(&body[1..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, payload);
+    }
+
+    /// Reads every frame in `wire` with a 64-byte limit.
+    async fn read_all(mut wire: &[u8]) -> io::Result<Vec<(Framing, Vec<u8>)>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut wire, 64).await? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    fn frame_too_large(error: io::Error) -> Framing {
+        let inner = error.into_inner().expect("FrameTooLarge error");
+        inner.downcast::<FrameTooLarge>().unwrap().framing
+    }
+
+    #[tokio::test]
+    async fn detects_each_framing_by_its_first_byte() {
+        let message = serde_json::json!({"type": "Ping"});
+        let mut wire = Vec::new();
+        for framing in [
+            Framing::Lines,
+            Framing::LengthPrefixed,
+            Framing::MessagePack,
+        ] {
+            let payload = framing.serialize(&message).unwrap();
+            wire.extend(framing.encode(&payload));
+        }
+        wire.extend_from_slice(b"{\"type\":\"Ping\"}\r\n");
+
+        let frames = read_all(&wire).await.unwrap();
+        let framings: Vec<Framing> = frames.iter().map(|(framing, _)| *framing).collect();
+        assert_eq!(
+            framings,
+            [
+                Framing::Lines,
+                Framing::LengthPrefixed,
+                Framing::MessagePack,
+                Framing::Lines,
+            ]
+        );
+        for (framing, payload) in frames {
+            let decoded: serde_json::Value = framing.deserialize(&payload).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_limit_are_rejected() {
+        let payload = vec![b'a'; 65];
+        for framing in [
+            Framing::Lines,
+            Framing::LengthPrefixed,
+            Framing::MessagePack,
+        ] {
+            let error = read_all(&framing.encode(&payload)).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(frame_too_large(error), framing);
+        }
+
+        let at_limit = vec![b'a'; 64];
+        for framing in [
+            Framing::Lines,
+            Framing::LengthPrefixed,
+            Framing::MessagePack,
+        ] {
+            let frames = read_all(&framing.encode(&at_limit)).await.unwrap();
+            assert_eq!(frames, [(framing, at_limit.clone())]);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_fail_at_eof() {
+        for framing in [Framing::LengthPrefixed, Framing::MessagePack] {
+            let frame = framing.encode(b"{\"type\":\"Ping\"}");
+            for cut in 1..frame.len() {
+                let error = read_all(&frame[..cut]).await.unwrap_err();
+                assert_eq!(
+                    error.kind(),
+                    io::ErrorKind::UnexpectedEof,
+                    "{framing:?} cut at {cut}"
+                );
+            }
+        }
+
+        // A last line without its newline still counts as a request.
+        let frames = read_all(b"{\"type\":\"Ping\"}").await.unwrap();
+        assert_eq!(frames, [(Framing::Lines, b"{\"type\":\"Ping\"}".to_vec())]);
+    }
+}
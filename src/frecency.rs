@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Largest score bonus history can add, so usage only reorders matches
+/// whose fuzzy scores are already close.
+const MAX_BONUS: f64 = 30.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FrecencyEntry {
+    count: u32,
+    /// Unix seconds of the most recent selection.
+    last_used: u64,
+}
+
+impl FrecencyEntry {
+    /// Selection count weighted by how recently the file was last used.
+    fn frecency(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last_used);
+        let recency = match age {
+            0..3_600 => 4.0,
+            3_600..86_400 => 2.0,
+            86_400..604_800 => 0.5,
+            _ => 0.25,
+        };
+        f64::from(self.count) * recency
+    }
+}
+
+/// Selection history used to boost frequently and recently opened files.
+#[derive(Debug, Default)]
+pub struct FrecencyTable {
+    entries: HashMap<String, FrecencyEntry>,
+    file: Option<PathBuf>,
+}
+
+impl FrecencyTable {
+    /// Loads the table stored at `file`, starting empty when it is missing
+    /// or unreadable. Later changes are saved back to the same file.
+    pub fn load(file: PathBuf) -> Self {
+        let entries = match std::fs::read(&file) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt history file {}: {}", file.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read history file {}: {}", file.display(), e);
+                HashMap::new()
+            }
+        };
+        Self {
+            entries,
+            file: Some(file),
+        }
+    }
+
+    /// Default location: `$XDG_DATA_HOME/qs-daemon/frecency.json`.
+    pub fn default_path() -> PathBuf {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(crate::home_dir()).join(".local/share"));
+        data_home.join("qs-daemon").join("frecency.json")
+    }
+
+    pub fn record(&mut self, path: &str) -> Result<()> {
+        let entry = self
+            .entries
+            .entry(path.to_string())
+            .or_insert(FrecencyEntry {
+                count: 0,
+                last_used: 0,
+            });
+        entry.count = entry.count.saturating_add(1);
+        entry.last_used = unix_now();
+        self.save()
+    }
+
+    /// Score bonus for `path`, zero when it was never selected.
+    pub fn bonus(&self, path: &str, now: u64) -> u32 {
+        self.entries.get(path).map_or(0, |entry| {
+            ((1.0 + entry.frecency(now)).ln() * 10.0).min(MAX_BONUS) as u32
+        })
+    }
+
+    /// Forgets every selection and deletes the history file. Returns how
+    /// many entries were dropped.
+    pub fn clear(&mut self) -> Result<usize> {
+        let removed = self.entries.len();
+        self.entries.clear();
+        if let Some(file) = &self.file {
+            match std::fs::remove_file(file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write next to the target and rename so a crash never leaves a
+        // truncated history behind.
+        let tmp = file.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
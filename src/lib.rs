@@ -0,0 +1,3330 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Fuzzy file search over an in-memory index of one or more directory
+//! trees. [`FileIndex`] scans its roots, keeps the file list and answers
+//! [`SearchRequest`]s synchronously, so it can be embedded in another
+//! program. The `qs-daemon` binary serves the same index over a socket with
+//! the [`DaemonRequest`] and [`DaemonResponse`] messages.
+
+use anyhow::Result;
+use nucleo_matcher::{
+    Config, Matcher, Utf32Str,
+    pattern::{Atom, AtomKind, CaseMatching, Normalization, Pattern},
+};
+use rayon::prelude::*;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// On-disk copy of the index for fast startup.
+pub mod cache;
+/// Selection history that boosts frequently and recently opened files.
+pub mod frecency;
+mod query_cache;
+/// Directory walking, either by spawning `fd` or in-process.
+pub mod scanner;
+mod scoped_query;
+mod suggest;
+
+use frecency::FrecencyTable;
+use query_cache::QueryCache;
+use scanner::{ExcludeMatcher, FileScanner, IgnoreRules, IncludeMatcher, ScanConfig, ScanOptions};
+use scoped_query::ScopedQuery;
+use suggest::TokenTable;
+
+/// Smallest slice of the index handed to a single search worker.
+const MIN_CHUNK_SIZE: usize = 4096;
+
+/// Files scored between checks for a newer search on the same connection.
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+/// Shortest query, in characters, worth prescreening files for.
+const PRESCREEN_MIN_CHARS: usize = 3;
+
+/// Number of best fuzzy hits re-ranked with the word-boundary bonus. It
+/// does not depend on the page, so every page sees the same order.
+const BOUNDARY_POOL: usize = 256;
+
+/// Compiled regexes kept before the cache is emptied.
+const REGEX_CACHE_CAPACITY: usize = 32;
+
+/// Default cap on the number of results one search may return.
+pub const DEFAULT_MAX_LIMIT: usize = 1000;
+
+/// Version of the [`DaemonRequest`] and [`DaemonResponse`] messages, bumped
+/// whenever a change would make an older peer misread them.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version the daemon still answers.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional stat() data, only collected when the index is built with
+/// `with_metadata(true)`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Modification time in unix seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_symlink: Option<bool>,
+    /// Device and inode number, shared by hard links to the same file.
+    #[serde(skip)]
+    pub inode: Option<(u64, u64)>,
+}
+
+impl FileMetadata {
+    fn read(path: &str) -> Self {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            return Self::default();
+        };
+        Self {
+            size: Some(meta.len()),
+            modified: meta
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|age| age.as_secs()),
+            is_symlink: Some(meta.file_type().is_symlink()),
+            #[cfg(unix)]
+            inode: {
+                use std::os::unix::fs::MetadataExt;
+                Some((meta.dev(), meta.ino()))
+            },
+            #[cfg(not(unix))]
+            inode: None,
+        }
+    }
+}
+
+/// What kind of file-system object an index entry is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryType {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+}
+
+/// One indexed file or directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Hash of `path`, the same across refreshes and restarts.
+    pub id: u64,
+    pub path: String,
+    pub display_path: String,
+    #[serde(default)]
+    pub entry_type: EntryType,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+/// A single change reported by the file-system watcher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEvent {
+    Created(PathBuf),
+    Removed(PathBuf),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchMatch {
+    /// Position of the matched character in `display_path`, counted in
+    /// Unicode scalar values.
+    pub char_index: u32,
+    /// Byte offset of the matched character in the UTF-8 `display_path`.
+    pub byte_index: u32,
+}
+
+/// A run of consecutive matched characters of `display_path`, as
+/// half-open ranges of character and byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchRange {
+    pub start: u32,
+    pub end: u32,
+    pub byte_start: u32,
+    pub byte_end: u32,
+}
+
+/// How a search reports the matched characters of each result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchFormat {
+    /// One [`SearchMatch`] per character in `matches`.
+    #[default]
+    Chars,
+    /// Runs of adjacent characters as [`MatchRange`]s in `match_ranges`,
+    /// leaving `matches` empty.
+    Ranges,
+}
+
+impl MatchFormat {
+    /// `matches` of `display_path` in this format, as the result's
+    /// `matches` and `match_ranges`.
+    fn apply(
+        self,
+        display_path: &str,
+        matches: Vec<SearchMatch>,
+    ) -> (Vec<SearchMatch>, Vec<MatchRange>) {
+        if self == MatchFormat::Chars {
+            return (matches, Vec::new());
+        }
+        let mut ranges: Vec<MatchRange> = Vec::new();
+        for found in matches {
+            let char_len = display_path[found.byte_index as usize..]
+                .chars()
+                .next()
+                .map_or(1, char::len_utf8) as u32;
+            match ranges.last_mut() {
+                Some(range) if range.end == found.char_index => {
+                    range.end += 1;
+                    range.byte_end += char_len;
+                }
+                _ => ranges.push(MatchRange {
+                    start: found.char_index,
+                    end: found.char_index + 1,
+                    byte_start: found.byte_index,
+                    byte_end: found.byte_index + char_len,
+                }),
+            }
+        }
+        (Vec::new(), ranges)
+    }
+}
+
+/// The part of `display_path` a result's matched characters fall in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchComponent {
+    Filename,
+    Directory,
+    Both,
+}
+
+/// One hit of a search, with the characters of `display_path` that matched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub display_path: String,
+    pub matches: Vec<SearchMatch>,
+    /// The same characters as `matches` when the search asked for
+    /// [`MatchFormat::Ranges`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub match_ranges: Vec<MatchRange>,
+    /// Where the matched characters lie; `None` when there are none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched_in: Option<MatchComponent>,
+    pub score: i32,
+    pub entry_type: EntryType,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+/// Which part of a path the query is scored against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchMode {
+    #[default]
+    Filename,
+    FullPath,
+    /// The file name and its directory are scored separately and combined
+    /// using [`MatchWeights`].
+    Weighted,
+}
+
+/// How much the file name and the directory count towards a
+/// [`MatchMode::Weighted`] score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchWeights {
+    pub filename: f32,
+    pub dir: f32,
+}
+
+impl Default for MatchWeights {
+    fn default() -> Self {
+        Self {
+            filename: 1.0,
+            dir: 0.5,
+        }
+    }
+}
+
+/// How the query is compared with file names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchMode {
+    /// Fuzzy matching scored by nucleo.
+    #[default]
+    Fuzzy,
+    /// The file name starts with the query.
+    Prefix,
+    /// The file name equals the query.
+    Exact,
+    /// The query is a regular expression matched against the file name,
+    /// or the whole display path in `FullPath` mode.
+    Regex,
+}
+
+/// How letter case is treated when matching.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaseMode {
+    #[default]
+    Ignore,
+    Respect,
+    /// Case-sensitive only when the query contains an uppercase letter.
+    Smart,
+}
+
+impl From<CaseMode> for CaseMatching {
+    fn from(mode: CaseMode) -> Self {
+        match mode {
+            CaseMode::Ignore => CaseMatching::Ignore,
+            CaseMode::Respect => CaseMatching::Respect,
+            CaseMode::Smart => CaseMatching::Smart,
+        }
+    }
+}
+
+/// Which of `path` and `display_path` a search fills in. The one left out
+/// is an empty string, omitted from the JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathFields {
+    #[default]
+    Both,
+    DisplayOnly,
+    AbsoluteOnly,
+}
+
+impl PathFields {
+    /// `path` and `display_path` of a result for `file`, shown as
+    /// `display_path`.
+    fn of(self, file: &FileEntry, display_path: Cow<'_, str>) -> (String, String) {
+        match self {
+            PathFields::Both => (file.path.clone(), display_path.into_owned()),
+            PathFields::DisplayOnly => (String::new(), display_path.into_owned()),
+            PathFields::AbsoluteOnly => (file.path.clone(), String::new()),
+        }
+    }
+}
+
+/// How a search writes each result's `display_path`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayStyle {
+    #[default]
+    Full,
+    /// Directories between the first one and the file name are cut to their
+    /// first character (two for dot-directories), and more than three of
+    /// them to the first and last around a `…`, so
+    /// `~/projects/qs/daemon/src/main.rs` becomes `~/p/…/s/main.rs`.
+    Shortened,
+}
+
+impl DisplayStyle {
+    /// `display_path` in this style, with `matches` moved to the same
+    /// characters in it. Matches on characters that were cut are dropped.
+    fn apply(
+        self,
+        display_path: &str,
+        matches: Vec<SearchMatch>,
+    ) -> (Cow<'_, str>, Vec<SearchMatch>) {
+        if self == DisplayStyle::Full {
+            return (Cow::Borrowed(display_path), matches);
+        }
+        let Some((short, kept)) = shorten_path(display_path) else {
+            return (Cow::Borrowed(display_path), matches);
+        };
+        let offsets: Vec<usize> = short.char_indices().map(|(byte, _)| byte).collect();
+        let matches = matches
+            .into_iter()
+            .filter_map(|found| {
+                let char_index = kept.get(found.char_index as usize).copied().flatten()?;
+                Some(SearchMatch {
+                    char_index: char_index as u32,
+                    byte_index: offsets[char_index] as u32,
+                })
+            })
+            .collect();
+        (Cow::Owned(short), matches)
+    }
+}
+
+/// The order of the files an empty query returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmptyOrder {
+    /// By `display_path`, so the list stays put across refreshes.
+    #[default]
+    Path,
+    /// Most often and recently selected first, then by path. Same as
+    /// `Path` when selection history is disabled.
+    Frecency,
+    /// Most recently modified first, then by path. Needs `--metadata`;
+    /// without it this is the same as `Path`.
+    Recent,
+}
+
+/// Whether accented letters in file names match their unaccented form.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NormMode {
+    /// `e` matches `é`, unless the query itself has accents.
+    #[default]
+    Smart,
+    /// Letters only match exactly as written.
+    Never,
+}
+
+impl From<NormMode> for Normalization {
+    fn from(mode: NormMode) -> Self {
+        match mode {
+            NormMode::Smart => Normalization::Smart,
+            NormMode::Never => Normalization::Never,
+        }
+    }
+}
+
+impl CaseMode {
+    /// Whether `query` is matched ignoring case.
+    fn ignores_case(self, query: &str) -> bool {
+        match self {
+            CaseMode::Ignore => true,
+            CaseMode::Respect => false,
+            CaseMode::Smart => !query.chars().any(char::is_uppercase),
+        }
+    }
+}
+
+/// A search for [`FileIndex::search`]. Only `query` is required when
+/// deserializing; every other field has a default.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchRequest {
+    pub query: String,
+    pub limit: Option<usize>,
+    /// Number of ranked results to skip before the returned page.
+    #[serde(default)]
+    pub offset: Option<usize>,
+    /// Falls back to the daemon's configured match mode.
+    #[serde(default)]
+    pub match_mode: Option<MatchMode>,
+    #[serde(default)]
+    pub case: Option<CaseMode>,
+    /// Accent folding for fuzzy matching, `Smart` by default.
+    #[serde(default)]
+    pub normalization: Option<NormMode>,
+    /// Only return entries of this type, e.g. `Dir` for a directory picker.
+    #[serde(default)]
+    pub entry_type: Option<EntryType>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Match the whole query as one verbatim substring, spaces included,
+    /// instead of fuzzy matching its space-separated words.
+    #[serde(default)]
+    pub literal: bool,
+    /// Only return paths ending in one of these extensions, compared
+    /// case-insensitively; a leading dot is optional.
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+    /// Fill in each result's `matches`; clients that do not highlight can
+    /// turn this off to save the work and the payload.
+    #[serde(default = "default_with_indices")]
+    pub with_indices: bool,
+    /// Points taken off each fuzzy score per `/` in the display path, so
+    /// shallower files rank higher. Falls back to the daemon's setting.
+    #[serde(default)]
+    pub depth_penalty: Option<u32>,
+    /// Only search files below this absolute directory, which must be
+    /// inside one of the roots.
+    #[serde(default)]
+    pub within: Option<String>,
+    /// Drop fuzzy matches scoring below this, after boosts and penalties.
+    #[serde(default)]
+    pub min_score: Option<i32>,
+    /// Check that each returned file still exists and drop those that do
+    /// not, at the cost of one stat() per result.
+    #[serde(default)]
+    pub verify_existence: bool,
+    /// Which of the daemon's indexes to search; the default one when unset.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Leave out `path` or `display_path` of every result.
+    #[serde(default)]
+    pub paths: PathFields,
+    /// Report matched characters one by one or as ranges.
+    #[serde(default)]
+    pub match_format: MatchFormat,
+    /// Abbreviate the directories in `display_path`.
+    #[serde(default)]
+    pub display_style: DisplayStyle,
+    /// Stop fuzzy scoring this many milliseconds after the search started
+    /// and return the best results found so far.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+    /// How files are ordered when `query` is empty.
+    #[serde(default)]
+    pub empty_order: EmptyOrder,
+}
+
+fn default_with_indices() -> bool {
+    true
+}
+
+/// One page of ranked results.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+    pub results_count: usize,
+    pub total_files: usize,
+    /// Whether results exist beyond `offset + limit`.
+    pub has_more: bool,
+    /// Whether the requested `limit` exceeded the daemon's maximum and was
+    /// lowered to it.
+    pub limit_capped: bool,
+    /// Time spent scoring and building the results.
+    pub took_ms: u64,
+    /// Files that matched the query before truncation to the page.
+    pub scored_files: usize,
+    /// Files the prescreen ruled out without fuzzy scoring them.
+    #[serde(default)]
+    pub skipped_files: usize,
+    /// Whether `deadline_ms` passed before every file was scored, so the
+    /// results are the best among the files scored in time.
+    #[serde(default)]
+    pub timed_out: bool,
+}
+
+/// A message from a client to the daemon, tagged by `type` on the wire.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DaemonRequest {
+    Search(SearchRequest),
+    /// Like `Search`, but answered with one `SearchResult` line per hit
+    /// followed by a `SearchEnd` line.
+    SearchStream(SearchRequest),
+    Refresh {
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    Status {
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    SetRefreshInterval {
+        secs: u64,
+    },
+    /// Reports that the user opened `path`, feeding frecency ranking.
+    RecordSelection {
+        path: String,
+    },
+    /// Routes every later response on this connection to a socket the
+    /// client listens on, or to the daemon's default response socket when
+    /// no path is given.
+    Subscribe {
+        #[serde(default)]
+        response_socket: Option<PathBuf>,
+    },
+    /// Optional first message on a connection, naming the protocol
+    /// version the client speaks. An incompatible client is answered with
+    /// an `Error` and disconnected.
+    Hello {
+        #[serde(default)]
+        client_version: Option<String>,
+        protocol_version: u32,
+        /// Ask for large responses to be gzip-compressed from now on. Only
+        /// honoured for length-prefixed and MessagePack framing.
+        #[serde(default)]
+        compression: bool,
+    },
+    /// Cheap liveness probe that never touches the index.
+    Ping,
+    /// Forgets every recorded selection.
+    ClearHistory,
+    /// Stops the daemon the same way SIGTERM does.
+    Shutdown,
+    /// Reports counters for monitoring.
+    Metrics,
+    /// Rescans only the directory `path`, which must be inside a root.
+    RefreshPath {
+        path: String,
+    },
+    /// Returns the most recently modified files, newest first.
+    Recent {
+        limit: usize,
+    },
+    /// Returns the indexed entry for exactly `path`.
+    Lookup {
+        path: String,
+    },
+    /// Scans `roots` with `exclude` in place of the configured excludes and
+    /// reports what the index would hold, without changing it.
+    ScanPreview {
+        roots: Vec<String>,
+        exclude: Vec<String>,
+    },
+    /// Suggests file name words and directory names that complete the
+    /// last word of `query`, most common first.
+    Suggest {
+        query: String,
+        limit: usize,
+    },
+    /// Returns groups of indexed files that are hard links to one another.
+    #[cfg(unix)]
+    Duplicates,
+    /// Lists the open connections with per-connection counters.
+    Connections,
+    /// Returns a page of the indexed entries, for debugging.
+    ListFiles {
+        #[serde(default)]
+        offset: usize,
+        limit: usize,
+    },
+    /// Re-reads the config file and applies what can change while running.
+    ReloadConfig,
+    /// Runs a throwaway search so the first real one is not slowed down by
+    /// lazy initialization.
+    Warmup,
+    /// Returns the settings in effect after merging flags, environment
+    /// variables and the config file.
+    GetConfig,
+}
+
+impl DaemonRequest {
+    /// The index the request names, if it names one.
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            DaemonRequest::Search(request) | DaemonRequest::SearchStream(request) => {
+                request.namespace.as_deref()
+            }
+            DaemonRequest::Refresh { namespace } | DaemonRequest::Status { namespace } => {
+                namespace.as_deref()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A root that could not be scanned, or scanned only partly, during the
+/// last full refresh.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootError {
+    pub root: PathBuf,
+    pub message: String,
+}
+
+/// The roots a full scan had trouble with, and whether it had to leave
+/// files out.
+#[derive(Debug, Clone, Default)]
+pub struct ScanProblems {
+    /// Roots that failed to scan.
+    pub errors: Vec<RootError>,
+    /// Roots whose scanner printed warnings but still succeeded.
+    pub warnings: Vec<RootError>,
+    /// More files were found than `max_files` allows, so some were dropped.
+    pub capped: bool,
+}
+
+/// How many indexed files live below one root.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RootStatus {
+    pub path: String,
+    pub files_count: usize,
+}
+
+/// The config file with command-line flags and environment variables
+/// applied over it, as the daemon is using it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub roots: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub refresh_secs: u64,
+    pub request_socket: PathBuf,
+    pub response_socket: PathBuf,
+    pub match_mode: MatchMode,
+    pub filename_weight: f32,
+    pub dir_weight: f32,
+    pub depth_penalty: u32,
+    /// Aliases for directory prefixes in display paths, by prefix.
+    pub path_aliases: BTreeMap<String, String>,
+    /// Shell command whose NUL-separated output is added to the index.
+    pub stream_command: Option<String>,
+    /// Fuzzy score bonus by file extension.
+    pub extension_boosts: BTreeMap<String, i32>,
+    /// Fuzzy score bonus per matched character that starts a word.
+    pub boundary_bonus: u32,
+}
+
+impl Settings {
+    /// The configured weights for [`MatchMode::Weighted`].
+    pub fn match_weights(&self) -> MatchWeights {
+        MatchWeights {
+            filename: self.filename_weight,
+            dir: self.dir_weight,
+        }
+    }
+}
+
+/// One open connection as reported by a `Connections` request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// `unix` for the request socket, otherwise the TCP peer address.
+    pub peer: String,
+    pub connected_secs: u64,
+    /// Requests answered so far, invalid ones included.
+    pub requests: u64,
+    /// Bytes of responses written, subscribed response socket included.
+    pub bytes_written: u64,
+}
+
+/// Indexed paths that are hard links to the same file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub size: u64,
+    /// Sorted by path.
+    pub paths: Vec<String>,
+}
+
+/// A completion of the last word of a query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    /// The query with its last word replaced by `token`.
+    pub completion: String,
+    pub token: String,
+    /// How many indexed entries contain `token`.
+    pub count: usize,
+}
+
+/// A message from the daemon to a client, tagged by `type` on the wire.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DaemonResponse {
+    SearchResults(SearchResponse),
+    SearchResult(SearchResult),
+    /// The search was abandoned because a newer one arrived on the same
+    /// connection.
+    SearchCancelled,
+    SearchEnd {
+        results_count: usize,
+        total_files: usize,
+        has_more: bool,
+        limit_capped: bool,
+        took_ms: u64,
+        scored_files: usize,
+        #[serde(default)]
+        skipped_files: usize,
+        #[serde(default)]
+        timed_out: bool,
+    },
+    RefreshComplete {
+        files_count: usize,
+        /// Roots that failed to scan; their files are missing from the index.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_errors: Vec<RootError>,
+        /// Roots that scanned with warnings, e.g. unreadable directories
+        /// whose files are missing; the messages are truncated.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_warnings: Vec<RootError>,
+    },
+    Status {
+        files_count: usize,
+        last_updated: u64,
+        scanner: String,
+        /// Approximate bytes held by indexed path strings.
+        index_bytes: usize,
+        /// How long the scanner took during the last full refresh; absent
+        /// until one has finished.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_scan_duration_ms: Option<u64>,
+        roots: Vec<RootStatus>,
+        active_clients: usize,
+        /// The index holds `--max-files` entries and may be missing files.
+        index_capped: bool,
+        /// Files found missing by `verify_existence` are still indexed.
+        stale: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_errors: Vec<RootError>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_warnings: Vec<RootError>,
+    },
+    RefreshIntervalSet {
+        secs: u64,
+    },
+    SelectionRecorded,
+    Subscribed,
+    Pong {
+        version: String,
+        uptime_secs: u64,
+    },
+    /// Accepts a `Hello`: the connection speaks `protocol_version`, and the
+    /// daemon supports every version from `min_protocol_version` to
+    /// `max_protocol_version`.
+    Hello {
+        daemon_version: String,
+        protocol_version: u32,
+        min_protocol_version: u32,
+        max_protocol_version: u32,
+        /// Whether every later response payload starts with a flag byte
+        /// saying if it is gzip-compressed.
+        #[serde(default)]
+        compression: bool,
+    },
+    HistoryCleared {
+        entries_removed: usize,
+    },
+    /// Sent in reply to `Shutdown`, before the daemon stops.
+    ShuttingDown,
+    Metrics {
+        /// Searches answered since startup, cancelled ones excluded.
+        searches_total: u64,
+        /// Successful refreshes since startup, periodic ones included.
+        refreshes_total: u64,
+        avg_search_ms: f64,
+        files_count: usize,
+        active_clients: usize,
+        uptime_secs: u64,
+    },
+    FileList {
+        files: Vec<FileEntry>,
+        total_files: usize,
+        has_more: bool,
+        limit_capped: bool,
+    },
+    Connections {
+        connections: Vec<ConnectionInfo>,
+    },
+    /// Hard-linked files, largest first.
+    Duplicates {
+        groups: Vec<DuplicateGroup>,
+    },
+    Suggestions {
+        suggestions: Vec<Suggestion>,
+    },
+    /// What a `ScanPreview` found; the index is unchanged.
+    ScanPreview {
+        files_count: usize,
+        /// The first few display paths, sorted.
+        sample: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_errors: Vec<RootError>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        root_warnings: Vec<RootError>,
+    },
+    ConfigReloaded {
+        /// The settings now in effect.
+        config: Settings,
+        /// Settings that changed in the file but only apply after a restart.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        restart_required: Vec<String>,
+        /// The roots or filters changed and a rescan is running.
+        rescanning: bool,
+    },
+    WarmedUp {
+        files_count: usize,
+        took_ms: u64,
+    },
+    Config {
+        config: Settings,
+        /// The file scanner in use, as in `Status`.
+        scanner: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// A scanned file list with the name index already sorted, so swapping it
+/// into a [`FileIndex`] with [`FileIndex::swap_files`] takes next to no
+/// time. Build it without holding the index lock.
+pub struct PreparedFiles {
+    files: Vec<FileEntry>,
+    name_order: Option<Vec<u32>>,
+    positions: HashMap<u64, usize>,
+    tokens: TokenTable,
+    problems: ScanProblems,
+    scan_duration: Option<Duration>,
+}
+
+impl PreparedFiles {
+    /// `sorted` should match [`FileIndex::has_name_index`] of the index the
+    /// files are meant for.
+    pub fn new(files: Vec<FileEntry>, problems: ScanProblems, sorted: bool) -> Self {
+        Self {
+            name_order: sorted.then(|| sorted_name_order(&files)),
+            positions: path_positions(&files),
+            tokens: TokenTable::build(&files),
+            files,
+            problems,
+            scan_duration: None,
+        }
+    }
+
+    /// Records how long the scan that produced the files took, for
+    /// [`FileIndex::last_scan_duration`].
+    pub fn with_scan_duration(mut self, scan_duration: Duration) -> Self {
+        self.scan_duration = Some(scan_duration);
+        self
+    }
+}
+
+/// Identifies one search on a connection. It becomes superseded as soon as
+/// a newer search arrives on the same connection, which lets scoring stop
+/// early instead of finishing work nobody will look at.
+#[derive(Debug, Clone)]
+pub struct SearchToken {
+    latest: Arc<AtomicU64>,
+    id: u64,
+}
+
+impl SearchToken {
+    /// The token of search `id`, superseded once `latest` holds a
+    /// different id. Bump `latest` as each new search arrives.
+    pub fn new(latest: Arc<AtomicU64>, id: u64) -> Self {
+        Self { latest, id }
+    }
+
+    /// A token that is never superseded.
+    pub fn detached() -> Self {
+        Self {
+            latest: Arc::new(AtomicU64::new(0)),
+            id: 0,
+        }
+    }
+
+    /// Whether a newer search has started, so this one can stop early.
+    pub fn is_superseded(&self) -> bool {
+        self.latest.load(Ordering::Relaxed) != self.id
+    }
+}
+
+/// The file list of one or more roots, with everything needed to rescan
+/// and search it. All methods are synchronous; searches take `&self` and
+/// spread scoring over the rayon thread pool.
+pub struct FileIndex {
+    scan: ScanConfig,
+    frecency: Option<FrecencyTable>,
+    cache_file: Option<PathBuf>,
+    match_mode: MatchMode,
+    match_weights: MatchWeights,
+    depth_penalty: u32,
+    /// Points added to fuzzy scores by file extension, longest extension
+    /// first so `tar.gz` wins over `gz`.
+    extension_boosts: Vec<(String, i32)>,
+    /// Points added per matched character at a word boundary.
+    boundary_bonus: u32,
+    prescreen: bool,
+    max_limit: usize,
+    files: Vec<FileEntry>,
+    /// Indices into `files` ordered by lowercased file name, kept when
+    /// enabled so `Prefix` and `Exact` searches can binary-search.
+    name_order: Option<Vec<u32>>,
+    /// Index in `files` of each entry by the hash of its path, for
+    /// [`FileIndex::lookup`].
+    positions: HashMap<u64, usize>,
+    /// Word counts for [`FileIndex::suggest`].
+    tokens: TokenTable,
+    /// Entries added with [`FileIndex::add_streamed`], merged back into
+    /// every rescan.
+    streamed: HashMap<String, EntryType>,
+    root_errors: Vec<RootError>,
+    root_warnings: Vec<RootError>,
+    /// Whether `max_files` has left files out since the last full scan.
+    capped: bool,
+    last_updated: std::time::SystemTime,
+    /// When the last full scan was swapped in. Watcher updates, partial
+    /// refreshes and a loaded cache do not count.
+    last_refresh: Option<Instant>,
+    /// How long the scanner ran for the last full scan.
+    last_scan_duration: Option<Duration>,
+    /// Set when a search with `verify_existence` found an indexed file
+    /// gone; cleared by the next full scan.
+    stale: AtomicBool,
+    /// Held while writing the cache file, which a shared borrow allows.
+    cache_lock: Mutex<()>,
+    query_cache: Mutex<QueryCache>,
+    /// Compiled regexes keyed by query and case-insensitivity.
+    regex_cache: Mutex<HashMap<(String, bool), Regex>>,
+}
+
+impl Default for FileIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileIndex {
+    /// An empty index of the home directory. Call [`FileIndex::update`] to
+    /// scan it.
+    pub fn new() -> Self {
+        Self::with_roots(vec![PathBuf::from(home_dir())])
+    }
+
+    /// An empty index of `roots`, with every option at its default.
+    pub fn with_roots(roots: Vec<PathBuf>) -> Self {
+        Self {
+            scan: ScanConfig {
+                roots,
+                scanner: scanner::detect(),
+                options: ScanOptions::default(),
+                aliases: PathAliases::default(),
+                collect_metadata: false,
+                max_files: None,
+            },
+            frecency: None,
+            cache_file: None,
+            match_mode: MatchMode::default(),
+            match_weights: MatchWeights::default(),
+            depth_penalty: 0,
+            extension_boosts: Vec::new(),
+            boundary_bonus: 0,
+            prescreen: true,
+            max_limit: DEFAULT_MAX_LIMIT,
+            files: Vec::new(),
+            name_order: None,
+            positions: HashMap::new(),
+            tokens: TokenTable::default(),
+            streamed: HashMap::new(),
+            root_errors: Vec::new(),
+            root_warnings: Vec::new(),
+            capped: false,
+            query_cache: Mutex::default(),
+            regex_cache: Mutex::default(),
+            // Never scanned; `Status` reports 0 until the first scan lands.
+            last_updated: std::time::UNIX_EPOCH,
+            last_refresh: None,
+            last_scan_duration: None,
+            stale: AtomicBool::new(false),
+            cache_lock: Mutex::default(),
+        }
+    }
+
+    /// Stat every file during `update` so results carry size, mtime and
+    /// symlink information.
+    pub fn with_metadata(mut self, enabled: bool) -> Self {
+        self.scan.collect_metadata = enabled;
+        self
+    }
+
+    /// Index directories as well as files.
+    pub fn with_dirs(mut self, enabled: bool) -> Self {
+        self.scan.options.include_dirs = enabled;
+        self
+    }
+
+    /// Index hidden (dot) files and directories.
+    pub fn with_hidden(mut self, enabled: bool) -> Self {
+        self.scan.options.include_hidden = enabled;
+        self
+    }
+
+    /// Follow symbolic links while scanning.
+    pub fn with_follow_symlinks(mut self, enabled: bool) -> Self {
+        self.scan.options.follow_symlinks = enabled;
+        self
+    }
+
+    /// Glob patterns that keep matching paths out of the index, see
+    /// [`ExcludeMatcher`] for how they are matched.
+    pub fn with_exclude(mut self, patterns: &[String]) -> Result<Self> {
+        self.scan.options.exclude = ExcludeMatcher::new(patterns)?;
+        Ok(self)
+    }
+
+    /// Glob patterns that, when given, restrict the index to matching
+    /// files, see [`IncludeMatcher`]. Excludes still win.
+    pub fn with_include(mut self, patterns: &[String]) -> Result<Self> {
+        self.scan.options.include = IncludeMatcher::new(patterns)?;
+        Ok(self)
+    }
+
+    /// Show paths under these prefixes by their alias, see [`PathAliases`].
+    pub fn with_path_aliases(mut self, aliases: PathAliases) -> Self {
+        self.scan.aliases = aliases;
+        self
+    }
+
+    /// Track selections in `history` and use them to boost search ranking.
+    pub fn with_frecency(mut self, history: FrecencyTable) -> Self {
+        self.frecency = Some(history);
+        self
+    }
+
+    /// Walk directories with `scanner` instead of the detected one.
+    pub fn with_scanner(mut self, scanner: Arc<dyn FileScanner>) -> Self {
+        self.scan.scanner = scanner;
+        self
+    }
+
+    /// Persist the file list to `cache_file` after every successful scan so
+    /// the next start can serve results before its first scan finishes.
+    pub fn with_cache(mut self, cache_file: PathBuf) -> Self {
+        self.cache_file = Some(cache_file);
+        self
+    }
+
+    /// Keep the files ordered by name on the side, so prefix and exact
+    /// searches take O(log n + matches) instead of a scan of every file.
+    /// Costs four bytes per file and a sort after every full scan.
+    pub fn with_name_index(mut self, enabled: bool) -> Self {
+        self.name_order = enabled.then(Vec::new);
+        self.rebuild_name_index();
+        self
+    }
+
+    /// Largest `limit` a single search may ask for; bigger requests are
+    /// capped.
+    pub fn with_max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = max_limit;
+        self
+    }
+
+    /// Weights used by [`MatchMode::Weighted`] searches.
+    pub fn with_match_weights(mut self, match_weights: MatchWeights) -> Self {
+        self.match_weights = match_weights;
+        self
+    }
+
+    /// Lower fuzzy scores by `points` per directory level, for searches
+    /// that do not set their own penalty.
+    pub fn with_depth_penalty(mut self, points: u32) -> Self {
+        self.depth_penalty = points;
+        self
+    }
+
+    /// Add `boosts` to the fuzzy score of files with these extensions
+    /// (negative values lower it). A leading dot is optional.
+    pub fn with_extension_boosts(mut self, boosts: &BTreeMap<String, i32>) -> Self {
+        self.extension_boosts = extension_boosts(boosts);
+        self
+    }
+
+    /// Add `points` to a fuzzy score for each matched character that
+    /// starts a word: the first character after `/`, `_`, `-`, `.` or a
+    /// space, or an uppercase letter after a lowercase one. Only the best
+    /// 256 hits are re-ranked this way, the same ones for every page.
+    pub fn with_boundary_bonus(mut self, points: u32) -> Self {
+        self.boundary_bonus = points;
+        self
+    }
+
+    /// Skip fuzzy scoring for files missing characters the query needs.
+    /// Results are the same either way; this only saves time.
+    pub fn with_prescreen(mut self, enabled: bool) -> Self {
+        self.prescreen = enabled;
+        self
+    }
+
+    /// Keep at most this many entries, evicting the least recently modified.
+    pub fn with_max_files(mut self, max_files: Option<usize>) -> Self {
+        self.scan.max_files = max_files;
+        self
+    }
+
+    /// Match mode used by searches that do not ask for one.
+    pub fn with_match_mode(mut self, match_mode: MatchMode) -> Self {
+        self.match_mode = match_mode;
+        self
+    }
+
+    /// Scans every root and replaces the file list, blocking until done.
+    /// From async code, scan a [`FileIndex::scan_config`] copy on a blocking
+    /// thread instead and pass the result to [`FileIndex::replace_files`].
+    pub fn update(&mut self) -> Result<()> {
+        info!("Updating file index...");
+        let started = Instant::now();
+        let (files, problems) = self.scan.scan()?;
+        let prepared = PreparedFiles::new(files, problems, self.has_name_index())
+            .with_scan_duration(started.elapsed());
+        self.swap_files(prepared);
+        self.save_cache();
+        Ok(())
+    }
+
+    /// A copy of the scan settings, for scanning without holding the index.
+    pub fn scan_config(&self) -> ScanConfig {
+        self.scan.clone()
+    }
+
+    /// Applies reloaded settings. Files already indexed stay as they are
+    /// until the next rescan.
+    pub fn reconfigure(
+        &mut self,
+        settings: &Settings,
+        exclude: ExcludeMatcher,
+        include: IncludeMatcher,
+    ) {
+        self.scan.roots = settings.roots.clone();
+        self.scan.options.exclude = exclude;
+        self.scan.options.include = include;
+        self.scan.aliases = PathAliases::new(settings.path_aliases.clone());
+        self.match_mode = settings.match_mode;
+        self.match_weights = settings.match_weights();
+        self.depth_penalty = settings.depth_penalty;
+        self.extension_boosts = extension_boosts(&settings.extension_boosts);
+        self.boundary_bonus = settings.boundary_bonus;
+        // The match settings feed into scores, so earlier results are stale.
+        self.query_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Records that the file list changed, which also invalidates every
+    /// cached search.
+    fn mark_updated(&mut self) {
+        self.last_updated = std::time::SystemTime::now();
+        self.query_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Swaps in the result of a completed scan and refreshes the cache.
+    pub fn replace_files(&mut self, files: Vec<FileEntry>, problems: ScanProblems) {
+        let prepared = PreparedFiles::new(files, problems, self.has_name_index());
+        self.swap_files(prepared);
+        self.save_cache();
+    }
+
+    /// Replaces the file list and everything derived from it in one step,
+    /// so a search sees either the old list or the new one. Does not write
+    /// the cache; call [`FileIndex::save_cache`] once the exclusive borrow
+    /// (or write lock) is released.
+    pub fn swap_files(&mut self, prepared: PreparedFiles) {
+        let PreparedFiles {
+            files,
+            name_order,
+            positions,
+            tokens,
+            problems,
+            scan_duration,
+        } = prepared;
+        self.name_order = self
+            .name_order
+            .is_some()
+            .then(|| name_order.unwrap_or_else(|| sorted_name_order(&files)));
+        self.files = files;
+        self.positions = positions;
+        self.tokens = tokens;
+        if scan_duration.is_some() {
+            self.last_scan_duration = scan_duration;
+        }
+        self.root_errors = problems.errors;
+        self.root_warnings = problems.warnings;
+        self.capped = problems.capped;
+        if self.merge_streamed() {
+            self.rebuild_name_index();
+        }
+        self.mark_updated();
+        self.last_refresh = Some(Instant::now());
+        *self.stale.get_mut() = false;
+        info!("Indexed {} files", self.files.len());
+    }
+
+    /// Whether [`FileIndex::with_name_index`] is on.
+    pub fn has_name_index(&self) -> bool {
+        self.name_order.is_some()
+    }
+
+    /// Swaps in a fresh scan of `dir`, leaving entries outside it untouched.
+    pub fn replace_subtree(&mut self, dir: &Path, files: Vec<FileEntry>) {
+        let Some(dir) = dir.to_str() else {
+            return;
+        };
+        let dir_prefix = format!("{}/", dir.trim_end_matches('/'));
+        self.files
+            .retain(|file| !file.path.starts_with(&dir_prefix));
+        info!("Reindexed {} files under {}", files.len(), dir);
+        self.files.extend(files);
+        if self.scan.limit_files(&mut self.files) {
+            self.capped = true;
+        }
+        self.merge_streamed();
+        self.rebuild_name_index();
+        self.mark_updated();
+        self.save_cache();
+    }
+
+    /// Adds paths from the stream command, with everything below those
+    /// that are directories. They pass the same excludes and includes as
+    /// scanned files and are kept across rescans until the watcher reports
+    /// them removed.
+    pub fn add_streamed(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            let Ok(meta) = std::fs::symlink_metadata(&path) else {
+                continue;
+            };
+            let mut entries = Vec::new();
+            if meta.is_dir() {
+                let include_hidden = self.scan.options.include_hidden;
+                entries.extend(walk_entries(&path, include_hidden, |_, _| false));
+                entries.push((path, EntryType::Dir));
+            } else if meta.is_file() {
+                entries.push((path, EntryType::File));
+            }
+            for (entry, entry_type) in entries {
+                if let Some(path) = entry.to_str() {
+                    self.streamed.insert(path.to_string(), entry_type);
+                }
+                self.insert_entry(&entry, entry_type);
+            }
+        }
+        self.mark_updated();
+    }
+
+    /// Appends the streamed entries `files` lacks that the scan settings
+    /// admit, in the room `max_files` leaves, and forgets those that no
+    /// longer exist. Returns whether any were added; call
+    /// [`FileIndex::rebuild_name_index`] afterwards.
+    fn merge_streamed(&mut self) -> bool {
+        if self.streamed.is_empty() {
+            return false;
+        }
+        let present: HashSet<&str> = self.files.iter().map(|file| file.path.as_str()).collect();
+        let missing: Vec<(String, EntryType)> = self
+            .streamed
+            .iter()
+            .filter(|(path, _)| !present.contains(path.as_str()))
+            .map(|(path, &entry_type)| (path.clone(), entry_type))
+            .collect();
+        drop(present);
+
+        let room = self.scan.max_files.map_or(usize::MAX, |max_files| {
+            max_files.saturating_sub(self.files.len())
+        });
+        let mut added = 0;
+        for (path, entry_type) in missing {
+            if !Path::new(&path).exists() {
+                self.streamed.remove(&path);
+                continue;
+            }
+            if !self.admits(Path::new(&path), entry_type) {
+                continue;
+            }
+            if added < room {
+                self.files.push(self.scan.make_entry(&path, entry_type));
+                added += 1;
+            } else {
+                self.capped = true;
+            }
+        }
+        added > 0
+    }
+
+    /// Re-sorts the name index, renumbers the path positions and recounts
+    /// the suggestion tokens after `files` was replaced or reordered.
+    fn rebuild_name_index(&mut self) {
+        if let Some(order) = &mut self.name_order {
+            *order = sorted_name_order(&self.files);
+        }
+        self.positions = path_positions(&self.files);
+        self.tokens = TokenTable::build(&self.files);
+    }
+
+    /// Drops the files `keep` rejects, renumbering the name index instead
+    /// of sorting it again.
+    fn retain_files(&mut self, mut keep: impl FnMut(&FileEntry) -> bool) {
+        let tokens = &mut self.tokens;
+        let mut keep = |file: &FileEntry| {
+            let kept = keep(file);
+            if !kept {
+                tokens.remove(file);
+            }
+            kept
+        };
+        let Some(order) = &mut self.name_order else {
+            self.files.retain(keep);
+            self.positions = path_positions(&self.files);
+            return;
+        };
+        let mut kept = 0;
+        let renumbered: Vec<Option<u32>> = self
+            .files
+            .iter()
+            .map(|file| {
+                keep(file).then(|| {
+                    kept += 1;
+                    kept - 1
+                })
+            })
+            .collect();
+        let mut keep_file = renumbered.iter();
+        self.files
+            .retain(|_| keep_file.next().is_some_and(Option::is_some));
+        order.retain_mut(|index| match renumbered[*index as usize] {
+            Some(new_index) => {
+                *index = new_index;
+                true
+            }
+            None => false,
+        });
+        self.positions = path_positions(&self.files);
+    }
+
+    /// Positions in `order` of the files whose lowercased name starts with
+    /// `needle`, which is already lowercase.
+    fn name_range(&self, order: &[u32], needle: &str) -> Range<usize> {
+        let key = |index: &u32| name_key(&self.files[*index as usize]);
+        let start = order.partition_point(|index| key(index).lt(needle.chars()));
+        let needle_chars = needle.chars().count();
+        let len = order[start..]
+            .partition_point(|index| key(index).take(needle_chars).eq(needle.chars()));
+        start..start + len
+    }
+
+    /// Writes the file list to the cache file, if there is one. Only needs
+    /// a shared borrow, so searches can run meanwhile; concurrent saves take
+    /// turns.
+    pub fn save_cache(&self) {
+        let _saving = self
+            .cache_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(cache_file) = &self.cache_file
+            && let Err(e) = cache::save(
+                cache_file,
+                &self.scan,
+                self.last_updated,
+                &self.files,
+                self.capped,
+            )
+        {
+            warn!(
+                "Failed to write index cache {}: {}",
+                cache_file.display(),
+                e
+            );
+        }
+    }
+
+    /// Loads the cached file list if it was written with the current scan
+    /// settings. Returns whether anything was loaded.
+    pub fn load_cache(&mut self) -> bool {
+        let Some(cache_file) = &self.cache_file else {
+            return false;
+        };
+        match cache::load(cache_file, &self.scan) {
+            Ok(Some((last_updated, files, capped))) => {
+                info!(
+                    "Loaded {} files from cache {}",
+                    files.len(),
+                    cache_file.display()
+                );
+                self.files = files;
+                self.capped = self.scan.limit_files(&mut self.files) || capped;
+                self.rebuild_name_index();
+                self.last_updated = last_updated;
+                self.query_cache
+                    .get_mut()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clear();
+                true
+            }
+            Ok(None) => {
+                info!("No usable index cache at {}", cache_file.display());
+                false
+            }
+            Err(e) => {
+                warn!("Ignoring index cache {}: {}", cache_file.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Runs one search. Fails only for an invalid `Regex` query.
+    pub fn search(&self, request: &SearchRequest) -> Result<SearchResponse> {
+        let response = self.search_cancellable(request, &SearchToken::detached())?;
+        Ok(response.expect("detached searches are never superseded"))
+    }
+
+    /// Scores every file against a throwaway query, which starts rayon's
+    /// threads and faults in the file list before the first real search.
+    /// Nothing is cached or recorded. Returns how long it took.
+    pub fn warmup(&self) -> Duration {
+        let started = Instant::now();
+        // A single character is below the prescreen threshold, so no file is
+        // skipped.
+        let request = SearchRequest {
+            query: "e".to_string(),
+            limit: Some(1),
+            offset: Some(0),
+            match_mode: Some(self.match_mode),
+            case: None,
+            normalization: None,
+            entry_type: None,
+            mode: SearchMode::Fuzzy,
+            literal: false,
+            extensions: None,
+            with_indices: true,
+            depth_penalty: Some(self.depth_penalty),
+            within: None,
+            min_score: None,
+            verify_existence: false,
+            namespace: None,
+            paths: PathFields::Both,
+            match_format: MatchFormat::Chars,
+            display_style: DisplayStyle::Full,
+            deadline_ms: None,
+            empty_order: EmptyOrder::Path,
+        };
+        // Fuzzy searches cannot fail.
+        let _ = self.run_search(&request, &SearchToken::detached(), started);
+        started.elapsed()
+    }
+
+    /// Same as [`FileIndex::search`], but returns `None` once `token` is
+    /// superseded.
+    pub fn search_cancellable(
+        &self,
+        request: &SearchRequest,
+        token: &SearchToken,
+    ) -> Result<Option<SearchResponse>> {
+        let started = Instant::now();
+        // Requests that only differ in how they spell the defaults share
+        // one cache entry.
+        let key = SearchRequest {
+            limit: Some(request.limit.unwrap_or(100)),
+            offset: Some(request.offset.unwrap_or(0)),
+            match_mode: Some(request.match_mode.unwrap_or(self.match_mode)),
+            depth_penalty: Some(request.depth_penalty.unwrap_or(self.depth_penalty)),
+            normalization: Some(request.normalization.unwrap_or_default()),
+            // A complete result answers any deadline; cut-short ones are not
+            // cached.
+            deadline_ms: None,
+            // Checking existence needs the absolute paths.
+            paths: if request.verify_existence && request.paths == PathFields::DisplayOnly {
+                PathFields::Both
+            } else {
+                request.paths
+            },
+            ..request.clone()
+        };
+        let cached = self
+            .query_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key, self.last_updated);
+        if let Some(mut response) = cached {
+            debug!("Serving {:?} from the query cache", request.query);
+            if request.verify_existence {
+                self.drop_missing(&mut response, request.paths);
+            }
+            response.took_ms = started.elapsed().as_millis() as u64;
+            return Ok(Some(response));
+        }
+
+        let search = SearchRequest {
+            deadline_ms: request.deadline_ms,
+            ..key
+        };
+        let Some(mut response) = self.run_search(&search, token, started)? else {
+            return Ok(None);
+        };
+        if !response.timed_out {
+            self.query_cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(
+                    SearchRequest {
+                        deadline_ms: None,
+                        ..search
+                    },
+                    self.last_updated,
+                    response.clone(),
+                );
+        }
+        if request.verify_existence {
+            self.drop_missing(&mut response, request.paths);
+            response.took_ms = started.elapsed().as_millis() as u64;
+        }
+        Ok(Some(response))
+    }
+
+    /// Removes results whose file is gone and marks the index stale if any
+    /// were found, then clears the absolute paths unless `paths` wants them.
+    fn drop_missing(&self, response: &mut SearchResponse, paths: PathFields) {
+        let before = response.results.len();
+        response
+            .results
+            .retain(|result| std::fs::symlink_metadata(&result.path).is_ok());
+        if paths == PathFields::DisplayOnly {
+            for result in &mut response.results {
+                result.path.clear();
+            }
+        }
+        let missing = before - response.results.len();
+        if missing > 0 {
+            debug!("Dropped {} results whose files no longer exist", missing);
+            response.results_count = response.results.len();
+            self.stale.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a search found indexed files that no longer exist since the
+    /// last full scan.
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    fn within_prefix(&self, within: &str) -> Result<String> {
+        if !Path::new(within).is_absolute() {
+            anyhow::bail!("within must be an absolute path: {}", within);
+        }
+        let canonical = std::fs::canonicalize(within)
+            .map_err(|e| anyhow::anyhow!("Cannot resolve {}: {}", within, e))?;
+        for root in &self.scan.roots {
+            let canonical_root = std::fs::canonicalize(root).unwrap_or_else(|_| root.clone());
+            if let Ok(relative) = canonical.strip_prefix(&canonical_root)
+                && let Some(dir) = root.join(relative).to_str()
+            {
+                return Ok(format!("{}/", dir.trim_end_matches('/')));
+            }
+        }
+        anyhow::bail!("{} is not inside an indexed root", within)
+    }
+
+    fn run_search(
+        &self,
+        request: &SearchRequest,
+        token: &SearchToken,
+        started: Instant,
+    ) -> Result<Option<SearchResponse>> {
+        let query = request.query.as_str();
+        let requested_limit = request.limit.unwrap_or(100);
+        let limit = requested_limit.min(self.max_limit);
+        let limit_capped = limit < requested_limit;
+        let offset = request.offset.unwrap_or(0);
+        // One extra hit beyond the page tells us whether more results exist.
+        let window = offset.saturating_add(limit).saturating_add(1);
+
+        let entry_type = request.entry_type;
+        let extensions: Vec<&str> = request
+            .extensions
+            .iter()
+            .flatten()
+            .map(|ext| ext.trim_start_matches('.'))
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        let within = request
+            .within
+            .as_deref()
+            .map(|dir| self.within_prefix(dir))
+            .transpose()?;
+        let wanted = |file: &FileEntry| {
+            entry_type.is_none_or(|kind| file.entry_type == kind)
+                && (extensions.is_empty() || has_extension(&file.path, &extensions))
+                && within
+                    .as_deref()
+                    .is_none_or(|prefix| file.path.starts_with(prefix))
+        };
+
+        if request.mode != SearchMode::Fuzzy {
+            let hits = if request.mode == SearchMode::Regex {
+                self.search_regex(request, wanted)?
+            } else {
+                self.search_anchored(request, wanted)
+            };
+            let scored_files = hits.len();
+            let has_more = hits.len() >= window;
+            let results = hits
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|(file, range)| {
+                    let matches = if request.with_indices {
+                        range_positions(&file.display_path, range)
+                    } else {
+                        Vec::new()
+                    };
+                    let matched_in = matched_component(file, &matches);
+                    let (display_path, matches) =
+                        request.display_style.apply(&file.display_path, matches);
+                    let (matches, match_ranges) =
+                        request.match_format.apply(&display_path, matches);
+                    let (path, display_path) = request.paths.of(file, display_path);
+                    SearchResult {
+                        id: file.id,
+                        path,
+                        display_path,
+                        matches,
+                        match_ranges,
+                        matched_in,
+                        score: 0,
+                        entry_type: file.entry_type,
+                        metadata: file.metadata.clone(),
+                    }
+                })
+                .collect();
+            return Ok(Some(self.response(
+                results,
+                has_more,
+                limit_capped,
+                scored_files,
+                started,
+            )));
+        }
+
+        if query.is_empty() {
+            let files = self.first_files(request.empty_order, window, wanted);
+            let has_more = files.len() > offset.saturating_add(limit);
+            let results: Vec<SearchResult> = files
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|file| {
+                    let (display_path, _) =
+                        request.display_style.apply(&file.display_path, Vec::new());
+                    let (path, display_path) = request.paths.of(file, display_path);
+                    SearchResult {
+                        id: file.id,
+                        path,
+                        display_path,
+                        matches: Vec::new(),
+                        match_ranges: Vec::new(),
+                        matched_in: None,
+                        score: 0,
+                        entry_type: file.entry_type,
+                        metadata: file.metadata.clone(),
+                    }
+                })
+                .collect();
+            return Ok(Some(self.response(
+                results,
+                has_more,
+                limit_capped,
+                0,
+                started,
+            )));
+        }
+
+        let case = request.case.unwrap_or_default();
+        let normalization = request.normalization.unwrap_or_default().into();
+        // `dir:`, `file:` and `ext:` terms are scored on their own; the
+        // rest of the query is matched as usual.
+        let scoped = (!request.literal)
+            .then(|| ScopedQuery::parse(query, case.into(), normalization))
+            .filter(ScopedQuery::is_scoped);
+        let query = scoped.as_ref().map_or(query, |scoped| scoped.rest.as_str());
+        let pattern = if request.literal {
+            // Pattern::new would still split on spaces; a lone atom keeps
+            // the query whole.
+            let mut pattern = Pattern::default();
+            pattern.atoms.push(Atom::new(
+                query,
+                case.into(),
+                normalization,
+                AtomKind::Substring,
+                false,
+            ));
+            pattern
+        } else {
+            Pattern::parse(query, case.into(), normalization)
+        };
+        debug!(
+            "Searching for {:?} ({} match)",
+            query,
+            if request.literal { "literal" } else { "fuzzy" }
+        );
+        let match_mode = request.match_mode.unwrap_or(self.match_mode);
+        // Characters each positive atom needs, for a cheap rejection of
+        // files that cannot match before the real scoring.
+        let needles: Vec<&[u8]> = pattern
+            .atoms
+            .iter()
+            .filter(|atom| !atom.negative)
+            .filter_map(|atom| match atom.needle_text() {
+                Utf32Str::Ascii(needle) => Some(needle),
+                Utf32Str::Unicode(_) => None,
+            })
+            .collect();
+        let prescreen = self.prescreen
+            && scoped.is_none()
+            && needles.iter().map(|needle| needle.len()).sum::<usize>() >= PRESCREEN_MIN_CHARS;
+        let prescreened = AtomicUsize::new(0);
+        let frecency = self.frecency.as_ref().filter(|table| !table.is_empty());
+        let depth_penalty = request.depth_penalty.unwrap_or(self.depth_penalty);
+        let min_score = request.min_score;
+        let now = frecency::unix_now();
+        let deadline = request
+            .deadline_ms
+            .map(|ms| started + Duration::from_millis(ms));
+        let timed_out = AtomicBool::new(false);
+        // With a boundary bonus, the whole re-ranked pool is kept even when
+        // the page ends before it.
+        let pool = if self.boundary_bonus > 0 {
+            window.max(BOUNDARY_POOL)
+        } else {
+            window
+        };
+
+        // Matcher is not Sync, so each chunk scores with its own and keeps
+        // only its best `pool` hits in a min-heap keyed by (score, index).
+        let chunk_size = self
+            .files
+            .len()
+            .div_ceil(rayon::current_num_threads())
+            .max(MIN_CHUNK_SIZE);
+        let scored_files = AtomicUsize::new(0);
+        let mut scored: Vec<Rank> = self
+            .files
+            .par_chunks(chunk_size)
+            .enumerate()
+            .flat_map_iter(|(chunk_index, chunk)| {
+                let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+                let mut haystack_vec = Vec::new();
+                let mut top = BinaryHeap::with_capacity(pool.min(chunk.len()) + 1);
+                let mut matched = 0;
+                let mut rejected = 0;
+
+                for (offset, file) in chunk.iter().enumerate() {
+                    if offset % CANCEL_CHECK_INTERVAL == 0 {
+                        if token.is_superseded() {
+                            break;
+                        }
+                        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                            timed_out.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                    if !wanted(file) {
+                        continue;
+                    }
+                    if prescreen && !may_match(&needles, prescreen_target(file, match_mode)) {
+                        rejected += 1;
+                        continue;
+                    }
+                    let score = score_file(
+                        &pattern,
+                        &mut matcher,
+                        &mut haystack_vec,
+                        file,
+                        match_mode,
+                        self.match_weights,
+                    );
+                    let score = match &scoped {
+                        Some(scoped) => score.and_then(|score| {
+                            Some(score + scoped.score(&mut matcher, &mut haystack_vec, file)?)
+                        }),
+                        None => score,
+                    };
+                    if let Some(score) = score {
+                        let score =
+                            score + frecency.map_or(0, |table| table.bonus(&file.path, now));
+                        let score = score.saturating_add_signed(self.extension_boost(&file.path));
+                        let score = if depth_penalty > 0 {
+                            score.saturating_sub(depth_penalty.saturating_mul(path_depth(file)))
+                        } else {
+                            score
+                        };
+                        if min_score.is_some_and(|min| i64::from(score) < i64::from(min)) {
+                            continue;
+                        }
+                        matched += 1;
+                        // The heap's top is the worst hit kept so far.
+                        top.push(rank(score, chunk_index * chunk_size + offset, file));
+                        if top.len() > pool {
+                            top.pop();
+                        }
+                    }
+                }
+                scored_files.fetch_add(matched, Ordering::Relaxed);
+                prescreened.fetch_add(rejected, Ordering::Relaxed);
+
+                top.into_vec()
+            })
+            .collect();
+
+        if token.is_superseded() {
+            return Ok(None);
+        }
+        let skipped_files = prescreened.into_inner();
+        if prescreen {
+            debug!(
+                "Prescreen skipped scoring {} of {} files",
+                skipped_files,
+                self.files.len()
+            );
+        }
+
+        // Every chunk kept up to `pool` hits; only the best `window`
+        // overall need a full sort.
+        if scored.len() > pool {
+            scored.select_nth_unstable(pool - 1);
+            scored.truncate(pool);
+        }
+        scored.sort_unstable();
+        if self.boundary_bonus > 0 {
+            // The bonus only raises scores, so the re-ranked pool still
+            // outranks every hit after it.
+            let reranked = scored.len().min(BOUNDARY_POOL);
+            let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+            let mut haystack_vec = Vec::new();
+            for (Reverse(score), _, _, index) in &mut scored[..reranked] {
+                let file = &self.files[*index];
+                let matches =
+                    match_highlights(&pattern, &mut matcher, &mut haystack_vec, file, match_mode);
+                let boundaries = boundary_matches(&file.display_path, &matches);
+                *score = score.saturating_add(self.boundary_bonus.saturating_mul(boundaries));
+            }
+            scored[..reranked].sort_unstable();
+            scored.truncate(window);
+        }
+        let has_more = scored.len() >= window;
+
+        let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+        let mut haystack_vec = Vec::new();
+        let results = scored
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(Reverse(score), _, _, index)| {
+                let file = &self.files[index];
+                let matches = if request.with_indices {
+                    let mut matches = match_highlights(
+                        &pattern,
+                        &mut matcher,
+                        &mut haystack_vec,
+                        file,
+                        match_mode,
+                    );
+                    if let Some(scoped) = &scoped {
+                        matches.extend(scoped.highlights(&mut matcher, &mut haystack_vec, file));
+                        matches.sort_unstable_by_key(|found| found.char_index);
+                        matches.dedup_by_key(|found| found.char_index);
+                    }
+                    matches
+                } else {
+                    Vec::new()
+                };
+
+                let matched_in = matched_component(file, &matches);
+                let (display_path, matches) =
+                    request.display_style.apply(&file.display_path, matches);
+                let (matches, match_ranges) = request.match_format.apply(&display_path, matches);
+                let (path, display_path) = request.paths.of(file, display_path);
+                SearchResult {
+                    id: file.id,
+                    path,
+                    display_path,
+                    matches,
+                    match_ranges,
+                    matched_in,
+                    score: score as i32,
+                    entry_type: file.entry_type,
+                    metadata: file.metadata.clone(),
+                }
+            })
+            .collect();
+
+        let mut response = self.response(
+            results,
+            has_more,
+            limit_capped,
+            scored_files.into_inner(),
+            started,
+        );
+        response.skipped_files = skipped_files;
+        response.timed_out = timed_out.into_inner();
+        Ok(Some(response))
+    }
+
+    /// The first `count` files `wanted` accepts in `order`, for an empty
+    /// query. Only those are fully sorted.
+    fn first_files(
+        &self,
+        order: EmptyOrder,
+        count: usize,
+        wanted: impl Fn(&FileEntry) -> bool + Sync,
+    ) -> Vec<&FileEntry> {
+        let now = frecency::unix_now();
+        let frecency = self.frecency.as_ref().filter(|table| !table.is_empty());
+        // Higher keys first; every order falls back to the path.
+        let key = |file: &FileEntry| match order {
+            EmptyOrder::Path => 0,
+            EmptyOrder::Frecency => {
+                frecency.map_or(0, |table| u64::from(table.bonus(&file.path, now)))
+            }
+            EmptyOrder::Recent => file.metadata.modified.unwrap_or(0),
+        };
+        let compare = |a: &(u64, &FileEntry), b: &(u64, &FileEntry)| {
+            b.0.cmp(&a.0)
+                .then_with(|| a.1.display_path.cmp(&b.1.display_path))
+                .then_with(|| a.1.path.cmp(&b.1.path))
+        };
+        let mut files: Vec<(u64, &FileEntry)> = self
+            .files
+            .par_iter()
+            .filter(|file| wanted(file))
+            .map(|file| (key(file), file))
+            .collect();
+        if files.len() > count && count > 0 {
+            files.select_nth_unstable_by(count - 1, compare);
+        }
+        files.truncate(count);
+        files.par_sort_unstable_by(compare);
+        files.into_iter().map(|(_, file)| file).collect()
+    }
+
+    /// Files whose name starts with (`Prefix`) or equals (`Exact`) the
+    /// query, sorted by path, with the byte range of the matched prefix.
+    fn search_anchored(
+        &self,
+        request: &SearchRequest,
+        wanted: impl Fn(&FileEntry) -> bool,
+    ) -> Vec<(&FileEntry, Range<usize>)> {
+        let query = request.query.as_str();
+        let ignore_case = request.case.unwrap_or_default().ignores_case(query);
+        let needle = if ignore_case {
+            query.to_lowercase()
+        } else {
+            query.to_string()
+        };
+        let needle_chars = query.chars().count();
+        let exact = request.mode == SearchMode::Exact;
+
+        let mut by_name;
+        let mut all;
+        let candidates: &mut dyn Iterator<Item = &FileEntry> = match &self.name_order {
+            Some(order) => {
+                let range = self.name_range(order, &query.to_lowercase());
+                by_name = order[range]
+                    .iter()
+                    .map(|&index| &self.files[index as usize]);
+                &mut by_name
+            }
+            None => {
+                all = self.files.iter();
+                &mut all
+            }
+        };
+        let mut hits: Vec<_> = candidates
+            .filter(|file| wanted(file))
+            .filter_map(|file| {
+                let name = match_target(file, MatchMode::Filename);
+                if !anchored_match(name, &needle, exact, ignore_case) {
+                    return None;
+                }
+                let start = target_offset(&file.display_path, name);
+                let len = name
+                    .char_indices()
+                    .nth(needle_chars)
+                    .map_or(name.len(), |(byte, _)| byte);
+                Some((file, start..start + len))
+            })
+            .collect();
+        hits.sort_unstable_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+        hits
+    }
+
+    /// Files whose name (or display path in `FullPath` mode) matches the
+    /// query as a regular expression, sorted by path, with the byte range
+    /// of the first match.
+    fn search_regex(
+        &self,
+        request: &SearchRequest,
+        wanted: impl Fn(&FileEntry) -> bool + Sync,
+    ) -> Result<Vec<(&FileEntry, Range<usize>)>> {
+        let query = request.query.as_str();
+        let ignore_case = request.case.unwrap_or_default().ignores_case(query);
+        let regex = self.compile_regex(query, ignore_case)?;
+        let match_mode = request.match_mode.unwrap_or(self.match_mode);
+
+        let mut hits: Vec<_> = self
+            .files
+            .par_iter()
+            .filter(|file| wanted(file))
+            .filter_map(|file| {
+                let target = match match_mode {
+                    MatchMode::FullPath | MatchMode::Weighted => file.display_path.as_str(),
+                    MatchMode::Filename => match_target(file, MatchMode::Filename),
+                };
+                let found = regex.find(target)?;
+                let start = target_offset(&file.display_path, target);
+                Some((file, start + found.start()..start + found.end()))
+            })
+            .collect();
+        hits.sort_unstable_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+        Ok(hits)
+    }
+
+    /// Compiles `query`, reusing the regex from an earlier search with the
+    /// same query so paging and re-issued searches skip the compilation.
+    fn compile_regex(&self, query: &str, ignore_case: bool) -> Result<Regex> {
+        let mut cache = self
+            .regex_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let key = (query.to_string(), ignore_case);
+        if let Some(regex) = cache.get(&key) {
+            return Ok(regex.clone());
+        }
+        let regex = RegexBuilder::new(query)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Invalid regex: {}", e))?;
+        if cache.len() >= REGEX_CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key, regex.clone());
+        Ok(regex)
+    }
+
+    fn response(
+        &self,
+        results: Vec<SearchResult>,
+        has_more: bool,
+        limit_capped: bool,
+        scored_files: usize,
+        started: Instant,
+    ) -> SearchResponse {
+        SearchResponse {
+            results_count: results.len(),
+            results,
+            total_files: self.files.len(),
+            has_more,
+            limit_capped,
+            took_ms: started.elapsed().as_millis() as u64,
+            scored_files,
+            skipped_files: 0,
+            timed_out: false,
+        }
+    }
+
+    /// Indexed files sharing a device and inode with another indexed file,
+    /// grouped and ordered by size, largest first. Needs the inode numbers
+    /// collected with `--metadata`.
+    #[cfg(unix)]
+    pub fn duplicates(&self) -> Result<Vec<DuplicateGroup>> {
+        if !self.scan.collect_metadata {
+            anyhow::bail!("finding duplicates needs the daemon to run with --metadata");
+        }
+        let mut by_inode: HashMap<(u64, u64), Vec<&FileEntry>> = HashMap::new();
+        for file in &self.files {
+            if file.entry_type != EntryType::File {
+                continue;
+            }
+            if let Some(inode) = file.metadata.inode {
+                by_inode.entry(inode).or_default().push(file);
+            }
+        }
+        let mut groups: Vec<DuplicateGroup> = by_inode
+            .into_values()
+            .filter(|files| files.len() > 1)
+            .map(|files| {
+                let mut paths: Vec<String> = files.iter().map(|file| file.path.clone()).collect();
+                paths.sort_unstable();
+                DuplicateGroup {
+                    size: files[0].metadata.size.unwrap_or(0),
+                    paths,
+                }
+            })
+            .collect();
+        groups.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+        Ok(groups)
+    }
+
+    /// Completions of the last word of `query` from the words of indexed
+    /// file names and the names of their directories, most common first.
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        self.tokens.suggest(query, limit.min(self.max_limit))
+    }
+
+    /// The `limit` most recently modified files, newest first. Needs the
+    /// modification times collected with `--metadata`.
+    pub fn recent(&self, limit: usize) -> Result<SearchResponse> {
+        if !self.scan.collect_metadata {
+            anyhow::bail!("recent files need the daemon to run with --metadata");
+        }
+        let started = Instant::now();
+        let page = limit.min(self.max_limit);
+
+        let mut dated: Vec<(Reverse<u64>, &str, usize)> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter_map(|(index, file)| {
+                let modified = file.metadata.modified?;
+                Some((Reverse(modified), file.path.as_str(), index))
+            })
+            .collect();
+        let dated_files = dated.len();
+        // Only the newest `page` entries need ordering.
+        if dated.len() > page && page > 0 {
+            dated.select_nth_unstable(page - 1);
+        }
+        dated.truncate(page);
+        dated.sort_unstable();
+
+        let results = dated
+            .into_iter()
+            .map(|(_, _, index)| {
+                let file = &self.files[index];
+                SearchResult {
+                    id: file.id,
+                    path: file.path.clone(),
+                    display_path: file.display_path.clone(),
+                    matches: Vec::new(),
+                    match_ranges: Vec::new(),
+                    matched_in: None,
+                    score: 0,
+                    entry_type: file.entry_type,
+                    metadata: file.metadata.clone(),
+                }
+            })
+            .collect();
+        Ok(self.response(
+            results,
+            dated_files > page,
+            page < limit,
+            dated_files,
+            started,
+        ))
+    }
+
+    /// One page of the indexed entries in index order, without scoring.
+    /// `limit` is clamped to the same maximum as searches.
+    pub fn list_files(&self, offset: usize, limit: usize) -> DaemonResponse {
+        let page = limit.min(self.max_limit);
+        let files: Vec<FileEntry> = self.files.iter().skip(offset).take(page).cloned().collect();
+        DaemonResponse::FileList {
+            has_more: offset.saturating_add(files.len()) < self.files.len(),
+            files,
+            total_files: self.files.len(),
+            limit_capped: page < limit,
+        }
+    }
+
+    /// Records that `path` was opened so it ranks higher in later searches.
+    pub fn record_selection(&mut self, path: &str) -> Result<()> {
+        match &mut self.frecency {
+            Some(table) => table.record(path)?,
+            None => anyhow::bail!("selection history is disabled"),
+        }
+        // Frecency feeds into scores, so earlier results are stale.
+        self.query_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        Ok(())
+    }
+
+    /// Drops the selection history. Does nothing when history is disabled.
+    pub fn clear_history(&mut self) -> Result<usize> {
+        let entries_removed = match &mut self.frecency {
+            Some(table) => table.clear()?,
+            None => 0,
+        };
+        self.query_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        Ok(entries_removed)
+    }
+
+    /// Applies a single watcher event without rescanning. Removing a
+    /// directory drops every indexed file beneath it.
+    pub fn apply_fs_event(&mut self, event: FsEvent) {
+        self.apply_fs_events([event]);
+    }
+
+    /// Applies a batch of watcher events without rescanning. Like the
+    /// scanners, paths ignored by `.gitignore`, `.ignore` or `.fdignore`
+    /// files are not added, so a build filling `target/` stays out.
+    pub fn apply_fs_events(&mut self, events: impl IntoIterator<Item = FsEvent>) {
+        let mut created = Vec::new();
+        let mut removed = HashSet::new();
+        for event in events {
+            match event {
+                FsEvent::Created(path) => created.push(path),
+                FsEvent::Removed(path) => {
+                    if let Some(path) = path.to_str() {
+                        removed.insert(path.trim_end_matches('/').to_string());
+                    }
+                }
+            }
+        }
+        // All removals in one pass over the files. Creations come after,
+        // and only add paths that still exist.
+        if !removed.is_empty() {
+            self.retain_files(|file| !is_removed(&removed, &file.path));
+            self.streamed.retain(|path, _| !is_removed(&removed, path));
+        }
+        let mut ignore = IgnoreRules::default();
+        for path in created {
+            self.add_created(&path, &mut ignore);
+        }
+        self.mark_updated();
+    }
+
+    fn add_created(&mut self, path: &Path, ignore: &mut IgnoreRules) {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            return;
+        };
+        if self.scan.is_ignored(ignore, path, meta.is_dir()) {
+            return;
+        }
+        if meta.is_dir() {
+            self.insert_entry(path, EntryType::Dir);
+            // Directories moved into a root arrive as a single event.
+            let include_hidden = self.scan.options.include_hidden;
+            let entries = walk_entries(path, include_hidden, |entry, is_dir| {
+                self.scan.is_ignored(ignore, entry, is_dir)
+            });
+            for (entry, entry_type) in entries {
+                self.insert_entry(&entry, entry_type);
+            }
+        } else if meta.is_file() {
+            self.insert_entry(path, EntryType::File);
+        }
+    }
+
+    /// Whether the scan settings let `path` into the index.
+    fn admits(&self, path: &Path, entry_type: EntryType) -> bool {
+        let options = &self.scan.options;
+        (entry_type != EntryType::Dir || options.include_dirs)
+            && !self.scan.is_excluded(path)
+            && (options.include_hidden || !self.scan.is_hidden(path))
+            && (entry_type == EntryType::Dir || options.include.is_included(path))
+    }
+
+    fn insert_entry(&mut self, path: &Path, entry_type: EntryType) {
+        let Some(path) = path.to_str().filter(|_| self.admits(path, entry_type)) else {
+            return;
+        };
+        if self.position(path).is_some() {
+            return;
+        }
+        if self.is_full() {
+            debug!("Index is full, not adding {}", path);
+            self.capped = true;
+            return;
+        }
+        let entry = self.scan.make_entry(path, entry_type);
+        if let Some(order) = &mut self.name_order {
+            let files = &self.files;
+            let at = order
+                .partition_point(|&index| name_key(&files[index as usize]).lt(name_key(&entry)));
+            order.insert(at, files.len() as u32);
+        }
+        self.positions
+            .insert(path_hash(&entry.path), self.files.len());
+        self.tokens.add(&entry);
+        self.files.push(entry);
+    }
+
+    /// Configured bonus for the extension of `path`, 0 when unlisted.
+    fn extension_boost(&self, path: &str) -> i32 {
+        self.extension_boosts
+            .iter()
+            .find(|(ext, _)| has_extension(path, std::slice::from_ref(ext)))
+            .map_or(0, |&(_, points)| points)
+    }
+
+    /// Index in `files` of the entry for `path`.
+    fn position(&self, path: &str) -> Option<usize> {
+        // Hashes can collide, so confirm the path matches.
+        self.positions
+            .get(&path_hash(path))
+            .copied()
+            .filter(|&index| self.files[index].path == path)
+    }
+
+    /// The indexed entry for exactly `path`, as a result with a score of 0,
+    /// found without scanning the file list.
+    pub fn lookup(&self, path: &str) -> Result<SearchResult> {
+        let Some(index) = self.position(path) else {
+            anyhow::bail!("Not indexed: {}", path);
+        };
+        let file = &self.files[index];
+        Ok(SearchResult {
+            id: file.id,
+            path: file.path.clone(),
+            display_path: file.display_path.clone(),
+            matches: Vec::new(),
+            match_ranges: Vec::new(),
+            matched_in: None,
+            score: 0,
+            entry_type: file.entry_type,
+            metadata: file.metadata.clone(),
+        })
+    }
+
+    /// The reply to a refresh: the file count and any failed or partly
+    /// scanned roots.
+    pub fn refresh_complete(&self) -> DaemonResponse {
+        DaemonResponse::RefreshComplete {
+            files_count: self.files.len(),
+            root_errors: self.root_errors.clone(),
+            root_warnings: self.root_warnings.clone(),
+        }
+    }
+
+    /// Whether `max_files` kept files out of the index since the last full
+    /// scan, so results may be missing some.
+    pub fn is_capped(&self) -> bool {
+        self.capped
+    }
+
+    /// Whether the index holds `max_files` entries and takes no more.
+    fn is_full(&self) -> bool {
+        self.scan
+            .max_files
+            .is_some_and(|max_files| self.files.len() >= max_files)
+    }
+
+    /// Roots that failed to scan during the last full refresh.
+    pub fn root_errors(&self) -> &[RootError] {
+        &self.root_errors
+    }
+
+    /// How long the scanner took during the last full refresh, `None` until
+    /// one has finished.
+    pub fn last_scan_duration(&self) -> Option<Duration> {
+        self.last_scan_duration
+    }
+
+    /// Roots that scanned with warnings during the last full refresh.
+    pub fn root_warnings(&self) -> &[RootError] {
+        &self.root_warnings
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.scan.roots
+    }
+
+    /// `fd` or `walkdir`, whichever scanner is in use.
+    pub fn scanner_name(&self) -> &'static str {
+        self.scan.scanner.name()
+    }
+
+    /// Number of indexed entries.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Bytes used by the path strings of every indexed file.
+    pub fn memory_usage(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.path.len() + file.display_path.len())
+            .sum()
+    }
+
+    /// Number of indexed files below each configured root.
+    pub fn root_counts(&self) -> Vec<RootStatus> {
+        self.scan
+            .roots
+            .iter()
+            .map(|root| RootStatus {
+                path: root.display().to_string(),
+                files_count: self
+                    .files
+                    .iter()
+                    .filter(|file| Path::new(&file.path).starts_with(root))
+                    .count(),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Time since the last full scan, if one has completed.
+    pub fn since_refresh(&self) -> Option<Duration> {
+        self.last_refresh.map(|refreshed| refreshed.elapsed())
+    }
+
+    /// Unix time of the last change to the file list, 0 before the first
+    /// scan.
+    pub fn last_updated_timestamp(&self) -> u64 {
+        self.last_updated
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// `$HOME`, which display paths abbreviate to `~`.
+pub fn home_dir() -> String {
+    std::env::var("HOME").unwrap_or_else(|_| "/home".to_string())
+}
+
+/// The part of a file's display path that is scored for `mode`.
+fn match_target(file: &FileEntry, mode: MatchMode) -> &str {
+    match mode {
+        MatchMode::Filename | MatchMode::Weighted => Path::new(&file.display_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(""),
+        MatchMode::FullPath => file.display_path.as_str(),
+    }
+}
+
+/// The text the prescreen checks: everything `mode` scores.
+fn prescreen_target(file: &FileEntry, mode: MatchMode) -> &str {
+    match mode {
+        MatchMode::Weighted => &file.display_path,
+        MatchMode::Filename | MatchMode::FullPath => match_target(file, mode),
+    }
+}
+
+/// Cheap necessary condition for a match: the characters of every needle
+/// appear in `haystack` in order, ignoring ASCII case. Non-ASCII haystacks
+/// always pass, since normalization can match them to ASCII needles.
+fn may_match(needles: &[&[u8]], haystack: &str) -> bool {
+    if !haystack.is_ascii() {
+        return true;
+    }
+    needles.iter().all(|needle| {
+        let mut rest = haystack.bytes();
+        needle
+            .iter()
+            .all(|&wanted| rest.any(|byte| byte == wanted || byte.to_ascii_lowercase() == wanted))
+    })
+}
+
+/// Number of directory levels in a file's display path.
+fn path_depth(file: &FileEntry) -> u32 {
+    file.display_path
+        .bytes()
+        .filter(|&byte| byte == b'/')
+        .count() as u32
+}
+
+/// Indices of `files` ordered by [`name_key`].
+fn sorted_name_order(files: &[FileEntry]) -> Vec<u32> {
+    let mut order: Vec<u32> = (0..files.len() as u32).collect();
+    order.par_sort_by_cached_key(|&index| name_key(&files[index as usize]).collect::<String>());
+    order
+}
+
+/// Index in `files` of each entry, by the hash of its path. Ids do not
+/// work here: two paths to the same file share one.
+fn path_positions(files: &[FileEntry]) -> HashMap<u64, usize> {
+    files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| (path_hash(&file.path), index))
+        .collect()
+}
+
+/// Lowercased file name, the sort key of the name index.
+fn name_key(file: &FileEntry) -> impl Iterator<Item = char> + '_ {
+    match_target(file, MatchMode::Filename)
+        .chars()
+        .flat_map(char::to_lowercase)
+}
+
+/// Byte offset of `target` within `display_path`, which it normally ends.
+fn target_offset(display_path: &str, target: &str) -> usize {
+    display_path.strip_suffix(target).map_or(0, str::len)
+}
+
+/// `display_path` shortened as described on [`DisplayStyle::Shortened`],
+/// with the new char index of each original char that was kept. `None`
+/// when there is nothing to shorten.
+fn shorten_path(display_path: &str) -> Option<(String, Vec<Option<usize>>)> {
+    let components: Vec<&str> = display_path.split('/').collect();
+    // An absolute path splits into an empty first component.
+    let head = if display_path.starts_with('/') { 2 } else { 1 };
+    let name = components.len().checked_sub(1)?;
+    if name <= head {
+        return None;
+    }
+    let middle = head..name;
+    let collapse = middle.len() > 3;
+
+    let mut short = String::with_capacity(display_path.len());
+    let mut kept = Vec::with_capacity(display_path.len());
+    let mut chars = 0;
+    for (i, component) in components.iter().enumerate() {
+        let dropped = collapse && i > middle.start && i + 1 < middle.end;
+        if dropped {
+            if i == middle.start + 1 {
+                short.push_str("/…");
+                chars += 2;
+            }
+            kept.extend(std::iter::repeat_n(None, component.chars().count() + 1));
+            continue;
+        }
+        if i > 0 {
+            short.push('/');
+            kept.push(Some(chars));
+            chars += 1;
+        }
+        let keep = if middle.contains(&i) {
+            if component.starts_with('.') { 2 } else { 1 }
+        } else {
+            usize::MAX
+        };
+        for (n, c) in component.chars().enumerate() {
+            if n < keep {
+                short.push(c);
+                kept.push(Some(chars));
+                chars += 1;
+            } else {
+                kept.push(None);
+            }
+        }
+    }
+    Some((short, kept))
+}
+
+/// Whether `matches` fall in the file name, its directory or both.
+fn matched_component(file: &FileEntry, matches: &[SearchMatch]) -> Option<MatchComponent> {
+    let name = match_target(file, MatchMode::Filename);
+    let name_start = target_offset(&file.display_path, name) as u32;
+    let in_name = matches.iter().any(|found| found.byte_index >= name_start);
+    let in_dir = matches.iter().any(|found| found.byte_index < name_start);
+    match (in_name, in_dir) {
+        (true, true) => Some(MatchComponent::Both),
+        (true, false) => Some(MatchComponent::Filename),
+        (false, true) => Some(MatchComponent::Directory),
+        (false, false) => None,
+    }
+}
+
+/// The parts of `display_path` scored separately in `mode`, each with the
+/// byte offset it starts at.
+fn match_parts(file: &FileEntry, mode: MatchMode) -> Vec<(usize, &str)> {
+    let target = match_target(file, mode);
+    let target_start = target_offset(&file.display_path, target);
+    match mode {
+        MatchMode::Weighted => vec![
+            (0, &file.display_path[..target_start]),
+            (target_start, target),
+        ],
+        MatchMode::Filename | MatchMode::FullPath => vec![(target_start, target)],
+    }
+}
+
+/// Scores `file` the way `mode` asks, or `None` when it does not match.
+fn score_file(
+    pattern: &Pattern,
+    matcher: &mut Matcher,
+    haystack_vec: &mut Vec<char>,
+    file: &FileEntry,
+    mode: MatchMode,
+    weights: MatchWeights,
+) -> Option<u32> {
+    if mode != MatchMode::Weighted {
+        let haystack = Utf32Str::new(match_target(file, mode), haystack_vec);
+        return pattern.score(haystack, matcher);
+    }
+
+    let name = match_target(file, mode);
+    let dir = &file.display_path[..target_offset(&file.display_path, name)];
+    let name_score = pattern.score(Utf32Str::new(name, haystack_vec), matcher);
+    let dir_score = pattern.score(Utf32Str::new(dir, haystack_vec), matcher);
+    if name_score.is_none() && dir_score.is_none() {
+        return None;
+    }
+    let combined = name_score.unwrap_or(0) as f32 * weights.filename
+        + dir_score.unwrap_or(0) as f32 * weights.dir;
+    Some(combined.round() as u32)
+}
+
+/// Positions within `display_path` of every character that matched, taken
+/// from each part that `mode` scores.
+fn match_highlights(
+    pattern: &Pattern,
+    matcher: &mut Matcher,
+    haystack_vec: &mut Vec<char>,
+    file: &FileEntry,
+    mode: MatchMode,
+) -> Vec<SearchMatch> {
+    let mut matches = Vec::new();
+    for (start, part) in match_parts(file, mode) {
+        let mut indices = Vec::new();
+        let haystack = Utf32Str::new(part, haystack_vec);
+        // A failed match may leave a partial set of positions behind.
+        if pattern.indices(haystack, matcher, &mut indices).is_some() {
+            matches.extend(match_positions(&file.display_path, start, part, indices));
+        }
+    }
+    matches
+}
+
+/// How many of `matches` start a word of `display_path`: they follow a
+/// separator, or are an uppercase letter following a lowercase one.
+fn boundary_matches(display_path: &str, matches: &[SearchMatch]) -> u32 {
+    let starts_word = |found: &&SearchMatch| {
+        let at = found.byte_index as usize;
+        let Some(current) = display_path[at..].chars().next() else {
+            return false;
+        };
+        match display_path[..at].chars().next_back() {
+            None => true,
+            Some(previous) => {
+                matches!(previous, '/' | '_' | '-' | '.' | ' ')
+                    || (previous.is_lowercase() && current.is_uppercase())
+            }
+        }
+    };
+    matches.iter().filter(starts_word).count() as u32
+}
+
+/// Translates nucleo match positions in `target`, which starts at byte
+/// `target_start` of `display_path`, into char and byte offsets within
+/// `display_path`. Every match mode and scoped term goes through here; for
+/// `FullPath` the target is the whole path and `target_start` is 0.
+fn match_positions(
+    display_path: &str,
+    target_start: usize,
+    target: &str,
+    mut indices: Vec<u32>,
+) -> Vec<SearchMatch> {
+    // Several atoms can report the same position, in any order.
+    indices.sort_unstable();
+    indices.dedup();
+
+    let prefix = &display_path[..target_start];
+    let prefix_chars = prefix.chars().count();
+    let units = match_units(target);
+    indices
+        .into_iter()
+        .filter_map(|idx| units.get(idx as usize))
+        .map(|&(char_index, byte_index)| SearchMatch {
+            char_index: (prefix_chars + char_index) as u32,
+            byte_index: (prefix.len() + byte_index) as u32,
+        })
+        .collect()
+}
+
+/// Char and byte offset of every unit nucleo indexes `text` by. Like
+/// `Utf32Str::new`, that is bytes when the text (or the first char of each
+/// grapheme cluster) is ASCII, and grapheme clusters otherwise.
+fn match_units(text: &str) -> Vec<(usize, usize)> {
+    if text.is_ascii() {
+        return (0..text.len()).map(|i| (i, i)).collect();
+    }
+
+    let graphemes: Vec<(usize, &str)> = text.grapheme_indices(true).collect();
+    if graphemes
+        .iter()
+        .all(|(_, grapheme)| grapheme.starts_with(|c: char| c.is_ascii()))
+    {
+        let mut units = Vec::with_capacity(text.len());
+        for (char_index, (byte_index, c)) in text.char_indices().enumerate() {
+            units.extend(std::iter::repeat_n((char_index, byte_index), c.len_utf8()));
+        }
+        return units;
+    }
+
+    let mut char_index = 0;
+    graphemes
+        .into_iter()
+        .map(|(byte_index, grapheme)| {
+            let unit = (char_index, byte_index);
+            char_index += grapheme.chars().count();
+            unit
+        })
+        .collect()
+}
+
+/// Whether `name` starts with (or, when `exact`, equals) `needle`, which
+/// is already lowercase when `ignore_case` is set.
+fn anchored_match(name: &str, needle: &str, exact: bool, ignore_case: bool) -> bool {
+    if !ignore_case {
+        return if exact {
+            name == needle
+        } else {
+            name.starts_with(needle)
+        };
+    }
+    let mut name_chars = name.chars().flat_map(char::to_lowercase);
+    needle.chars().all(|c| name_chars.next() == Some(c)) && (!exact || name_chars.next().is_none())
+}
+
+/// Positions of every character of `display_path` within the byte
+/// `range`.
+fn range_positions(display_path: &str, range: Range<usize>) -> Vec<SearchMatch> {
+    let prefix_chars = display_path[..range.start].chars().count();
+    display_path[range.clone()]
+        .char_indices()
+        .enumerate()
+        .map(|(char_index, (byte_index, _))| SearchMatch {
+            char_index: (prefix_chars + char_index) as u32,
+            byte_index: (range.start + byte_index) as u32,
+        })
+        .collect()
+}
+
+/// Sort key for a scored hit; smaller keys rank first. Equal scores fall
+/// back to the shorter display path, then byte-wise display path order, so
+/// results never depend on scan order or thread timing.
+type Rank<'a> = (Reverse<u32>, usize, &'a str, usize);
+
+fn rank(score: u32, index: usize, file: &FileEntry) -> Rank<'_> {
+    (
+        Reverse(score),
+        file.display_path.len(),
+        &file.display_path,
+        index,
+    )
+}
+
+/// `boosts` without leading dots, longest extension first.
+fn extension_boosts(boosts: &BTreeMap<String, i32>) -> Vec<(String, i32)> {
+    let mut boosts: Vec<(String, i32)> = boosts
+        .iter()
+        .map(|(ext, &points)| (ext.trim_start_matches('.').to_string(), points))
+        .filter(|(ext, _)| !ext.is_empty())
+        .collect();
+    boosts.sort_by_key(|(ext, _)| Reverse(ext.len()));
+    boosts
+}
+
+/// Whether `path` ends in `.ext` for one of `extensions`, ignoring ASCII
+/// case. Multi-part extensions such as `tar.gz` work too.
+fn has_extension(path: &str, extensions: &[impl AsRef<str>]) -> bool {
+    let path = path.as_bytes();
+    extensions.iter().any(|ext| {
+        let ext = ext.as_ref().as_bytes();
+        path.len() > ext.len()
+            && path[path.len() - ext.len() - 1] == b'.'
+            && path[path.len() - ext.len()..].eq_ignore_ascii_case(ext)
+    })
+}
+
+/// Whether `path` or one of its parent directories is in `removed`.
+fn is_removed(removed: &HashSet<String>, path: &str) -> bool {
+    removed.contains(path)
+        || path
+            .match_indices('/')
+            .any(|(end, _)| end > 0 && removed.contains(&path[..end]))
+}
+
+/// Recursively lists regular files and directories below `dir`, skipping
+/// hidden ones unless `include_hidden` is set and those `skip` rejects
+/// (given the path and whether it is a directory).
+fn walk_entries(
+    dir: &Path,
+    include_hidden: bool,
+    mut skip: impl FnMut(&Path, bool) -> bool,
+) -> Vec<(PathBuf, EntryType)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if !include_hidden && entry.file_name().as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() && !skip(&path, true) => {
+                    files.push((path.clone(), EntryType::Dir));
+                    pending.push(path);
+                }
+                Ok(kind) if kind.is_file() && !skip(&path, false) => {
+                    files.push((path, EntryType::File));
+                }
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+/// Stable id of the file at `path`: the hash of its canonical path, so the
+/// same file reached through a symlink or a non-canonical path keeps one
+/// id. Falls back to `path` itself when it cannot be resolved, such as
+/// once the file is gone.
+pub fn file_id(path: &str) -> u64 {
+    match std::fs::canonicalize(path) {
+        Ok(canonical) => canonical
+            .to_str()
+            .map_or_else(|| path_hash(path), path_hash),
+        Err(_) => path_hash(path),
+    }
+}
+
+/// 64-bit FNV-1a hash of `path`, which unlike the std hashers is fixed
+/// across Rust versions and runs.
+pub(crate) fn path_hash(path: &str) -> u64 {
+    path.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Directory prefixes shown under a short alias in `display_path`, such as
+/// `/mnt/projects` as `@projects`. The home directory is shown as `~` unless
+/// an alias for it is given. The longest matching prefix wins, and only
+/// whole path components are replaced, so `/home/username` stays as is when
+/// the prefix is `/home/user`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathAliases {
+    /// Prefixes without a trailing slash, longest first.
+    aliases: Vec<(String, String)>,
+}
+
+impl Default for PathAliases {
+    fn default() -> Self {
+        Self::new([])
+    }
+}
+
+impl PathAliases {
+    pub fn new(aliases: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self::with_home(home_dir(), aliases)
+    }
+
+    fn with_home(home: String, aliases: impl IntoIterator<Item = (String, String)>) -> Self {
+        let by_prefix: BTreeMap<String, String> = std::iter::once((home, "~".to_string()))
+            .chain(aliases)
+            .map(|(prefix, alias)| (prefix.trim_end_matches('/').to_string(), alias))
+            .filter(|(prefix, _)| !prefix.is_empty())
+            .collect();
+        let mut aliases: Vec<(String, String)> = by_prefix.into_iter().collect();
+        aliases.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        Self { aliases }
+    }
+
+    /// Every prefix with its alias, longest prefix first.
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.aliases
+    }
+
+    /// `path` with its longest aliased prefix replaced.
+    pub fn display_path(&self, path: &str) -> String {
+        for (prefix, alias) in &self.aliases {
+            match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    return format!("{}{}", alias, rest);
+                }
+                _ => {}
+            }
+        }
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(display_path: &str) -> FileEntry {
+        FileEntry {
+            id: file_id(display_path),
+            path: display_path.to_string(),
+            display_path: display_path.to_string(),
+            entry_type: EntryType::File,
+            metadata: FileMetadata::default(),
+        }
+    }
+
+    /// `(char_index, byte_index)` of every highlighted character.
+    fn highlights(display_path: &str, query: &str, mode: MatchMode) -> Vec<(u32, u32)> {
+        let pattern = Pattern::parse(query, CaseMatching::Smart, Normalization::Smart);
+        let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+        let mut haystack_vec = Vec::new();
+        match_highlights(
+            &pattern,
+            &mut matcher,
+            &mut haystack_vec,
+            &entry(display_path),
+            mode,
+        )
+        .into_iter()
+        .map(|found| (found.char_index, found.byte_index))
+        .collect()
+    }
+
+    /// `index` holding exactly `display_paths`, used as paths too.
+    fn with_files(mut index: FileIndex, display_paths: &[&str]) -> FileIndex {
+        let files = display_paths.iter().map(|path| entry(path)).collect();
+        let sorted = index.has_name_index();
+        index.swap_files(PreparedFiles::new(files, ScanProblems::default(), sorted));
+        index
+    }
+
+    fn search(index: &FileIndex, request: serde_json::Value) -> SearchResponse {
+        let request: SearchRequest = serde_json::from_value(request).unwrap();
+        index.search(&request).unwrap()
+    }
+
+    /// What a client sees of each result: the path, its score and the
+    /// highlighted characters.
+    fn outcome(response: &SearchResponse) -> Vec<(String, i32, Vec<u32>)> {
+        response
+            .results
+            .iter()
+            .map(|result| {
+                let matches = result.matches.iter().map(|found| found.char_index);
+                (result.path.clone(), result.score, matches.collect())
+            })
+            .collect()
+    }
+
+    /// `(char_index, byte_index)` of every match in the top result of a
+    /// search over `display_paths`.
+    fn search_matches(display_paths: &[&str], query: &str, mode: MatchMode) -> Vec<(u32, u32)> {
+        let index = with_files(FileIndex::new().with_match_mode(mode), display_paths);
+        let response = search(&index, serde_json::json!({ "query": query, "limit": 1 }));
+        response.results[0]
+            .matches
+            .iter()
+            .map(|found| (found.char_index, found.byte_index))
+            .collect()
+    }
+
+    #[test]
+    fn match_units_of_ascii_text_are_bytes() {
+        assert_eq!(match_units("a.rs"), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn match_units_of_non_ascii_text_are_chars() {
+        assert_eq!(
+            match_units("né.rs"),
+            vec![(0, 0), (1, 1), (2, 3), (3, 4), (4, 5)]
+        );
+    }
+
+    #[test]
+    fn match_positions_offsets_by_target_start() {
+        let found = match_positions("~/café/naïve.rs", 8, "naïve.rs", vec![4, 2, 2]);
+        let found: Vec<(u32, u32)> = found
+            .into_iter()
+            .map(|found| (found.char_index, found.byte_index))
+            .collect();
+        assert_eq!(found, vec![(9, 10), (11, 13)]);
+    }
+
+    #[test]
+    fn highlights_ascii_filename() {
+        assert_eq!(
+            highlights("~/src/main.rs", "mrs", MatchMode::Filename),
+            vec![(6, 6), (11, 11), (12, 12)]
+        );
+    }
+
+    #[test]
+    fn highlights_ascii_full_path() {
+        assert_eq!(
+            highlights("~/src/main.rs", "src", MatchMode::FullPath),
+            vec![(2, 2), (3, 3), (4, 4)]
+        );
+    }
+
+    #[test]
+    fn highlights_non_ascii_filename() {
+        assert_eq!(
+            highlights("~/café/naïve.rs", "ive", MatchMode::Filename),
+            vec![(9, 10), (10, 12), (11, 13)]
+        );
+    }
+
+    #[test]
+    fn highlights_non_ascii_full_path() {
+        assert_eq!(
+            highlights("~/café/naïve.rs", "naive", MatchMode::FullPath),
+            vec![(7, 8), (8, 9), (9, 10), (10, 12), (11, 13)]
+        );
+    }
+
+    #[test]
+    fn highlights_combining_marks() {
+        assert_eq!(
+            highlights("~/cafe\u{301}.rs", "rs", MatchMode::Filename),
+            vec![(8, 9), (9, 10)]
+        );
+    }
+
+    #[test]
+    fn search_reports_offsets_past_multibyte_directory() {
+        // "é" in the directory is two bytes, so every position after it
+        // is one byte further along than it is in chars.
+        let paths = ["~/café/naïve.rs", "~/src/lib.rs"];
+        let filename = search_matches(&paths, "vers", MatchMode::Filename);
+        assert_eq!(filename, vec![(10, 12), (11, 13), (13, 15), (14, 16)]);
+        let full_path = search_matches(&paths, "cafnaive", MatchMode::FullPath);
+        assert_eq!(
+            full_path,
+            vec![
+                (2, 2),
+                (3, 3),
+                (4, 4),
+                (7, 8),
+                (8, 9),
+                (9, 10),
+                (10, 12),
+                (11, 13)
+            ]
+        );
+        let path = paths[0];
+        for (char_index, byte_index) in filename.into_iter().chain(full_path) {
+            assert_eq!(
+                path.char_indices().nth(char_index as usize).unwrap().0,
+                byte_index as usize
+            );
+        }
+    }
+
+    fn aliases(home: &str, aliases: &[(&str, &str)]) -> PathAliases {
+        PathAliases::with_home(
+            home.to_string(),
+            aliases
+                .iter()
+                .map(|(prefix, alias)| (prefix.to_string(), alias.to_string())),
+        )
+    }
+
+    #[test]
+    fn home_is_shown_as_tilde_with_or_without_trailing_slash() {
+        for home in ["/home/user", "/home/user/"] {
+            let aliases = aliases(home, &[]);
+            assert_eq!(
+                aliases.display_path("/home/user/src/main.rs"),
+                "~/src/main.rs"
+            );
+            assert_eq!(aliases.display_path("/home/user"), "~");
+        }
+    }
+
+    #[test]
+    fn aliases_replace_whole_components_only() {
+        let aliases = aliases("/home/user", &[("/mnt/projects", "@projects")]);
+        assert_eq!(
+            aliases.display_path("/home/username/a.rs"),
+            "/home/username/a.rs"
+        );
+        assert_eq!(
+            aliases.display_path("/mnt/projects2/a.rs"),
+            "/mnt/projects2/a.rs"
+        );
+        assert_eq!(aliases.display_path("/mnt/projects/a.rs"), "@projects/a.rs");
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let aliases = aliases("/home/user", &[("/home/user/work/", "@work")]);
+        assert_eq!(aliases.display_path("/home/user/work/a.rs"), "@work/a.rs");
+        assert_eq!(aliases.display_path("/home/user/a.rs"), "~/a.rs");
+    }
+
+    #[test]
+    fn alias_for_home_replaces_tilde() {
+        let aliases = aliases("/home/user", &[("/home/user", "@home")]);
+        assert_eq!(aliases.display_path("/home/user/a.rs"), "@home/a.rs");
+    }
+
+    #[test]
+    fn prescreen_does_not_change_results() {
+        let mut paths: Vec<String> = ["src", "tests", "docs/guide", "Config", "café"]
+            .iter()
+            .flat_map(|dir| {
+                [
+                    "main.rs",
+                    "lib.rs",
+                    "MainWindow.qml",
+                    "test_utils.py",
+                    "naïve.rs",
+                    "READ ME.md",
+                ]
+                .iter()
+                .map(move |name| format!("~/{}/{}", dir, name))
+            })
+            .collect();
+        paths.extend((0..500).map(|n| format!("~/data/file_{}.bin", n)));
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let screened = with_files(FileIndex::new(), &paths);
+        let unscreened = with_files(FileIndex::new().with_prescreen(false), &paths);
+
+        let queries = [
+            "main",
+            "Main",
+            "MAIN",
+            "mainwin",
+            "naive",
+            "naïve",
+            "café",
+            "src main",
+            "docs rs",
+            "^main",
+            "^lib",
+            ".rs$",
+            "qml$",
+            "!test main",
+            "!^src rs",
+            "'utils",
+            "read me",
+            "Read",
+            "zzz",
+        ];
+        let mut skipped = 0;
+        for query in queries {
+            for match_mode in ["Filename", "FullPath", "Weighted"] {
+                let request = serde_json::json!({
+                    "query": query,
+                    "limit": 1000,
+                    "match_mode": match_mode,
+                });
+                let expected = search(&unscreened, request.clone());
+                let actual = search(&screened, request);
+                assert_eq!(
+                    outcome(&actual),
+                    outcome(&expected),
+                    "{:?} in {} mode",
+                    query,
+                    match_mode
+                );
+                assert_eq!(actual.scored_files, expected.scored_files);
+                assert_eq!(expected.skipped_files, 0);
+                skipped += actual.skipped_files;
+            }
+        }
+        assert!(skipped > 0);
+    }
+
+    #[test]
+    fn prescreen_skips_files_lacking_the_query() {
+        let mut paths: Vec<String> = (0..1000)
+            .map(|n| format!("~/data/file_{}.bin", n))
+            .collect();
+        paths.push("~/src/main.rs".to_string());
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let index = with_files(FileIndex::new(), &paths);
+        let response = search(&index, serde_json::json!({ "query": "main" }));
+        assert_eq!(outcome(&response)[0].0, "~/src/main.rs");
+        assert_eq!(response.skipped_files, 1000);
+    }
+
+    #[test]
+    fn file_id_follows_symlinks_to_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::fs::write(real.join("a.rs"), "").unwrap();
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+
+        let id = file_id(real.join("a.rs").to_str().unwrap());
+        let through_link = dir.path().join("link/a.rs");
+        let dotted = real.join("../real/./a.rs");
+        assert_eq!(file_id(through_link.to_str().unwrap()), id);
+        assert_eq!(file_id(dotted.to_str().unwrap()), id);
+
+        let gone = "/nonexistent/qs-daemon/a.rs";
+        assert_eq!(file_id(gone), path_hash(gone));
+    }
+
+    #[test]
+    fn anchored_results_do_not_depend_on_the_name_index() {
+        let paths = [
+            "~/src/main.rs",
+            "~/src/Main.java",
+            "~/src/mainline/lib.rs",
+            "~/docs/main.md",
+            "~/a/b/main.rs",
+            "~/tmp/domain.rs",
+            "~/tmp/MAIN",
+            "~/héllo/mañana.txt",
+        ];
+        let plain = with_files(FileIndex::new(), &paths);
+        let sorted = with_files(FileIndex::new().with_name_index(true), &paths);
+        assert!(sorted.has_name_index());
+        for (query, mode) in [
+            ("ma", "Prefix"),
+            ("Ma", "Prefix"),
+            ("main", "Prefix"),
+            ("mañ", "Prefix"),
+            ("main.rs", "Exact"),
+            ("MAIN", "Exact"),
+            ("lib", "Prefix"),
+            ("zzz", "Prefix"),
+        ] {
+            let request = serde_json::json!({ "query": query, "mode": mode, "limit": 100 });
+            let expected = outcome(&search(&plain, request.clone()));
+            assert_eq!(
+                outcome(&search(&sorted, request)),
+                expected,
+                "{mode} {query}"
+            );
+        }
+        let request = serde_json::json!({ "query": "main", "mode": "Prefix" });
+        assert_eq!(search(&sorted, request).results_count, 5);
+    }
+
+    #[test]
+    fn equal_scores_rank_the_shorter_display_path_first() {
+        let index = with_files(
+            FileIndex::new().with_match_mode(MatchMode::Filename),
+            &[
+                "~/a/b/c/main.rs",
+                "~/zz/main.rs",
+                "~/ab/main.rs",
+                "~/main.rs",
+            ],
+        );
+        let response = search(&index, serde_json::json!({ "query": "main.rs" }));
+        let results = outcome(&response);
+        assert!(results.iter().all(|(_, score, _)| *score == results[0].1));
+        let paths: Vec<&str> = results.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "~/main.rs",
+                "~/ab/main.rs",
+                "~/zz/main.rs",
+                "~/a/b/c/main.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn pages_cover_every_result_once() {
+        let paths: Vec<String> = (0..25)
+            .map(|i| format!("~/dir{}/mod{i}.rs", i % 4))
+            .collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let index = with_files(FileIndex::new(), &paths);
+        let all = search(&index, serde_json::json!({ "query": "mr", "limit": 100 }));
+        assert_eq!(all.results_count, 25);
+
+        let mut paged = Vec::new();
+        for offset in (0..25).step_by(10) {
+            let page = search(
+                &index,
+                serde_json::json!({ "query": "mr", "limit": 10, "offset": offset }),
+            );
+            assert_eq!(page.has_more, offset + 10 < 25, "offset {offset}");
+            paged.extend(outcome(&page));
+        }
+        assert_eq!(paged, outcome(&all));
+    }
+
+    fn scan_dir_with_max_files(files: usize, max_files: usize) -> FileIndex {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..files {
+            std::fs::write(dir.path().join(format!("f{i}.txt")), "").unwrap();
+        }
+        let mut index = FileIndex::with_roots(vec![dir.path().to_path_buf()])
+            .with_scanner(Arc::new(scanner::WalkdirScanner))
+            .with_max_files(Some(max_files));
+        index.update().unwrap();
+        index
+    }
+
+    #[test]
+    fn index_at_exactly_max_files_is_not_capped() {
+        let index = scan_dir_with_max_files(5, 5);
+        assert_eq!(index.files.len(), 5);
+        assert!(!index.is_capped());
+    }
+
+    #[test]
+    fn index_over_max_files_is_capped() {
+        let index = scan_dir_with_max_files(6, 5);
+        assert_eq!(index.files.len(), 5);
+        assert!(index.is_capped());
+    }
+}
@@ -1,484 +1,1768 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use anyhow::Result;
-use nucleo_matcher::{
-    Config, Matcher, Utf32Str,
-    pattern::{CaseMatching, Normalization, Pattern},
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use qs_daemon::cache;
+use qs_daemon::frecency::FrecencyTable;
+use qs_daemon::scanner::{ExcludeMatcher, FileListScanner, IncludeMatcher};
+use qs_daemon::{
+    DEFAULT_MAX_LIMIT, DaemonRequest, DaemonResponse, FileIndex, MIN_PROTOCOL_VERSION,
+    MatchWeights, PROTOCOL_VERSION, PathAliases, PreparedFiles, SearchResponse, SearchToken,
+    Settings, home_dir,
 };
-use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{UnixListener, UnixStream};
-use tokio::time::{Duration, sleep};
-use tracing::{debug, error, info, warn};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FileEntry {
-    pub path: String,
-    pub display_path: String,
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{RwLock, Semaphore, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing::{Instrument, debug, error, info, info_span, warn};
+
+mod config;
+mod connections;
+mod framing;
+mod metrics;
+mod stream_command;
+mod subscriber;
+mod systemd;
+mod watcher;
+
+use connections::{ConnectionStats, Connections};
+use framing::{FrameTooLarge, Framing};
+use metrics::Metrics;
+use subscriber::ResponseSubscriber;
+
+/// Requests read ahead of the one currently being handled.
+const REQUEST_QUEUE: usize = 32;
+
+/// Attempts to repeat a write that failed with a transient error.
+const WRITE_RETRIES: u32 = 5;
+
+/// Wait before the first write retry; it doubles on each further attempt.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How long shutdown waits for connected clients before dropping them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a client turned away for exceeding `--max-clients` gets to
+/// read the error before its connection is closed.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Fuzzy file search daemon")]
+struct Args {
+    /// Directory to index (repeatable, defaults to $HOME)
+    #[arg(long = "root", env = "QS_ROOTS", value_delimiter = ':')]
+    roots: Vec<PathBuf>,
+
+    /// Seconds between full rescans; 0 disables periodic refresh [default: 300]
+    #[arg(long, env = "QS_REFRESH_SECS")]
+    refresh_secs: Option<u64>,
+
+    /// Seconds a refresh may run before it is cancelled; 0 disables the
+    /// timeout
+    #[arg(long, env = "QS_REFRESH_TIMEOUT", default_value_t = 600)]
+    refresh_timeout: u64,
+
+    /// Glob pattern to leave out of the index (repeatable)
+    #[arg(long, env = "QS_EXCLUDE", value_delimiter = ':')]
+    exclude: Vec<String>,
+
+    /// Glob pattern a file must match to be indexed, e.g. `*.rs`
+    /// (repeatable; excludes still apply)
+    #[arg(long, env = "QS_INCLUDE", value_delimiter = ':')]
+    include: Vec<String>,
+
+    /// Index directories as well as files
+    #[arg(long, env = "QS_INCLUDE_DIRS")]
+    include_dirs: bool,
+
+    /// Index hidden files and directories (fd's --hidden)
+    #[arg(long, env = "QS_INCLUDE_HIDDEN")]
+    include_hidden: bool,
+
+    /// Follow symbolic links while scanning (fd's --follow)
+    #[arg(long, env = "QS_FOLLOW_SYMLINKS")]
+    follow_symlinks: bool,
+
+    /// Index at most this many entries, keeping the most recently modified
+    #[arg(long, env = "QS_MAX_FILES")]
+    max_files: Option<usize>,
+
+    /// Include size, modification time and symlink flag in results
+    #[arg(long, env = "QS_METADATA")]
+    metadata: bool,
+
+    /// Socket the daemon listens on for requests
+    /// [default: $XDG_RUNTIME_DIR/qs-daemon/request.sock]
+    #[arg(long, env = "QS_REQUEST_SOCKET")]
+    request_socket: Option<PathBuf>,
+
+    /// Socket the GUI listens on for responses
+    /// [default: $XDG_RUNTIME_DIR/qs-daemon/response.sock]
+    #[arg(long, env = "QS_RESPONSE_SOCKET")]
+    response_socket: Option<PathBuf>,
+
+    /// Log output format
+    #[arg(long, env = "QS_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Largest number of results a single search may return
+    #[arg(long, env = "QS_MAX_LIMIT", default_value_t = DEFAULT_MAX_LIMIT)]
+    max_limit: usize,
+
+    /// Most clients connected at once; further connections get a "too many
+    /// clients" error and are closed
+    #[arg(
+        long,
+        env = "QS_MAX_CLIENTS",
+        default_value_t = 128,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    max_clients: u32,
+
+    /// Largest request in bytes; a client sending more gets an error and is
+    /// disconnected
+    #[arg(
+        long,
+        env = "QS_MAX_REQUEST_BYTES",
+        default_value_t = 1024 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..=framing::MAX_REQUEST_LIMIT as u64),
+    )]
+    max_request_bytes: u64,
+
+    /// Gzip responses larger than this many bytes for clients that ask for
+    /// compression in their Hello
+    #[arg(long, env = "QS_COMPRESS_MIN_BYTES", default_value_t = 16 * 1024)]
+    compress_min_bytes: usize,
+
+    /// Do not record opened files or boost them in search results
+    #[arg(long, env = "QS_NO_HISTORY")]
+    no_history: bool,
+
+    /// Start serving immediately and run the first scan in the background
+    #[arg(long, env = "QS_LAZY_INIT")]
+    lazy_init: bool,
+
+    /// Do not keep an on-disk copy of the index for fast startup
+    #[arg(long, env = "QS_NO_CACHE")]
+    no_cache: bool,
+
+    /// Also accept requests over TCP on this address, e.g. 127.0.0.1:7070.
+    /// There is no authentication, so prefer a loopback address
+    #[arg(long, env = "QS_LISTEN_TCP")]
+    listen_tcp: Option<SocketAddr>,
+
+    /// Refuse `Shutdown` requests, so only a signal can stop the daemon
+    #[arg(long, env = "QS_NO_SHUTDOWN_REQUEST")]
+    no_shutdown_request: bool,
+
+    /// Keep file names sorted so Prefix and Exact searches binary-search
+    /// instead of checking every file
+    #[arg(long, env = "QS_SORTED_INDEX")]
+    sorted_index: bool,
+
+    /// Score every file instead of first skipping those that cannot match
+    #[arg(long, env = "QS_NO_PRESCREEN")]
+    no_prescreen: bool,
+
+    /// Index the paths listed in this file (newline- or NUL-separated)
+    /// instead of walking the roots; implies --no-watch
+    #[arg(long, env = "QS_FILE_LIST")]
+    file_list: Option<PathBuf>,
+
+    /// Disable the file-system watcher and rely on periodic rescans only
+    #[arg(long, env = "QS_NO_WATCH")]
+    no_watch: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SearchMatch {
-    pub char_index: u32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per event, for log aggregators
+    Json,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SearchResult {
-    pub path: String,
-    pub display_path: String,
-    pub matches: Vec<SearchMatch>,
-    pub score: i32,
-}
+impl Args {
+    /// Whether the file-system watcher runs. A file list is only re-read on
+    /// refresh, so it is never watched.
+    fn watching(&self) -> bool {
+        !self.no_watch && self.file_list.is_none()
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SearchRequest {
-    pub query: String,
-    pub limit: Option<usize>,
+    fn refresh_timeout(&self) -> Option<Duration> {
+        (self.refresh_timeout > 0).then(|| Duration::from_secs(self.refresh_timeout))
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SearchResponse {
-    pub results: Vec<SearchResult>,
-    pub results_count: usize,
-    pub total_files: usize,
+/// Applies flags and environment variables over the config file.
+fn resolve_settings(args: &Args, config: config::Config) -> Settings {
+    let roots = if !args.roots.is_empty() {
+        args.roots.clone()
+    } else if !config.roots.is_empty() {
+        config.roots
+    } else {
+        vec![PathBuf::from(home_dir())]
+    };
+    // Relative roots would make every indexed path relative too.
+    let roots = roots
+        .into_iter()
+        .map(|root| std::path::absolute(&root).unwrap_or(root))
+        .collect();
+    let exclude = if args.exclude.is_empty() {
+        config.exclude
+    } else {
+        args.exclude.clone()
+    };
+    let include = if args.include.is_empty() {
+        config.include
+    } else {
+        args.include.clone()
+    };
+    let socket_dir = default_socket_dir();
+    Settings {
+        roots,
+        exclude,
+        include,
+        refresh_secs: args.refresh_secs.or(config.refresh_secs).unwrap_or(300),
+        request_socket: args
+            .request_socket
+            .clone()
+            .or(config.request_socket)
+            .unwrap_or_else(|| socket_dir.join("request.sock")),
+        response_socket: args
+            .response_socket
+            .clone()
+            .or(config.response_socket)
+            .unwrap_or_else(|| socket_dir.join("response.sock")),
+        match_mode: config.match_mode,
+        filename_weight: config
+            .filename_weight
+            .unwrap_or(MatchWeights::default().filename),
+        dir_weight: config.dir_weight.unwrap_or(MatchWeights::default().dir),
+        depth_penalty: config.depth_penalty.unwrap_or(0),
+        path_aliases: config.path_aliases,
+        stream_command: config.stream_command,
+        extension_boosts: config.extension_boosts,
+        boundary_bonus: config.boundary_bonus.unwrap_or(0),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum DaemonRequest {
-    Search { query: String, limit: Option<usize> },
-    Refresh,
-    Status,
+/// Name a request can use for the index configured at the top level.
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// An index served in addition to the default one, configured under
+/// `[namespaces.<name>]`.
+struct Namespace {
+    file_index: Arc<RwLock<FileIndex>>,
+    /// The table the namespace was built from, so a reload can tell
+    /// whether it changed.
+    config: config::NamespaceConfig,
+    /// Held so the namespace's periodic refresh keeps running.
+    _refresh_interval: watch::Sender<Duration>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum DaemonResponse {
-    SearchResults(SearchResponse),
-    RefreshComplete {
-        files_count: usize,
-    },
-    Status {
-        files_count: usize,
-        last_updated: u64,
-    },
-    Error {
-        message: String,
-    },
+/// The index `namespace` names, or the default one.
+fn namespace_index(
+    default: &Arc<RwLock<FileIndex>>,
+    namespaces: &BTreeMap<String, Namespace>,
+    namespace: Option<&str>,
+) -> Result<Arc<RwLock<FileIndex>>> {
+    match namespace {
+        None | Some(DEFAULT_NAMESPACE) => Ok(Arc::clone(default)),
+        Some(name) => namespaces
+            .get(name)
+            .map(|namespace| Arc::clone(&namespace.file_index))
+            .ok_or_else(|| anyhow::anyhow!("Unknown namespace: {}", name)),
+    }
 }
 
-pub struct FileIndex {
-    files: Vec<FileEntry>,
-    last_updated: std::time::SystemTime,
-    matcher: Matcher,
+/// Where a client connected from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Peer {
+    /// The Unix request socket, or an in-memory pipe.
+    Unix,
+    /// The `--listen-tcp` listener, which anyone who can reach it may use.
+    Tcp(SocketAddr),
 }
 
-impl Default for FileIndex {
-    fn default() -> Self {
-        Self::new()
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Unix => f.write_str("unix"),
+            Peer::Tcp(addr) => addr.fmt(f),
+        }
     }
 }
 
-impl FileIndex {
-    pub fn new() -> Self {
-        Self {
-            files: Vec::new(),
-            last_updated: std::time::SystemTime::now(),
-            matcher: Matcher::new(Config::DEFAULT.match_paths()),
+/// State shared by every connection of the request server.
+#[derive(Clone)]
+struct ServerContext {
+    file_index: Arc<RwLock<FileIndex>>,
+    /// Named indexes besides `file_index`, which is the default one.
+    namespaces: Arc<BTreeMap<String, Namespace>>,
+    default_response_socket: PathBuf,
+    connections: Arc<Connections>,
+    /// One permit per connection allowed by `--max-clients`.
+    client_slots: Arc<Semaphore>,
+    refresh_interval: watch::Sender<Duration>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    /// Set to `true` to begin a graceful shutdown.
+    shutdown: watch::Sender<bool>,
+    /// Whether clients may stop the daemon with a `Shutdown` request.
+    allow_shutdown_request: bool,
+    /// Flags and environment variables, which keep precedence over the
+    /// config file when it is reloaded.
+    args: Arc<Args>,
+    settings: Arc<Mutex<Settings>>,
+}
+
+/// Serves one connection. Any bidirectional byte stream works: the Unix
+/// socket, the TCP listener, or an in-memory `tokio::io::duplex` pipe.
+/// `peer` names the client in `Connections` responses.
+async fn handle_client<S>(stream: S, peer: Peer, ctx: ServerContext) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut shutdown = ctx.shutdown.subscribe();
+    let ServerContext {
+        file_index,
+        namespaces,
+        default_response_socket,
+        connections,
+        refresh_interval,
+        started,
+        metrics,
+        ..
+    } = ctx;
+    let refresh_timeout = ctx.args.refresh_timeout();
+    let connection = connections.open(peer.to_string());
+    debug!("Client connected. Active clients: {}", connections.len());
+    let (reader, mut fallback_writer) = tokio::io::split(stream);
+    // Responses go back on the request connection until the client
+    // subscribes a response socket of its own.
+    let mut subscriber: Option<ResponseSubscriber> = None;
+    let latest_search = Arc::new(AtomicU64::new(0));
+    let (request_tx, mut request_rx) = mpsc::channel(REQUEST_QUEUE);
+    let reader_task = tokio::spawn(read_requests(
+        reader,
+        ctx.args.max_request_bytes as usize,
+        latest_search,
+        request_tx,
+    ));
+
+    let mut stop_after_reply = false;
+    // Set once the client asks for compression: responses larger than this
+    // are gzipped.
+    let mut compress_above: Option<usize> = None;
+    loop {
+        // Only stop between requests so an in-flight one is always answered.
+        let (request, token, framing) = tokio::select! {
+            received = request_rx.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        // Every log line for this request carries both ids.
+        let request_number = connection.record_request();
+        let span = info_span!(
+            "request",
+            connection = connection.id(),
+            request = request_number
+        );
+        let flow = async {
+            let mut close_after_reply = false;
+            let mut compress_after_reply = false;
+            // Requests naming a namespace run against that index instead.
+            let request = request.and_then(|request| {
+                let index = namespace_index(&file_index, &namespaces, request.namespace())?;
+                Ok((request, index))
+            });
+            let response = match request {
+                Ok((request, file_index)) => match request {
+                    DaemonRequest::Search(request) => {
+                        let search_started = Instant::now();
+                        let searched = on_index(&file_index, move |index| {
+                            index.search_cancellable(&request, &token)
+                        })
+                        .await
+                        .and_then(|searched| searched);
+                        match searched {
+                            Ok(Some(response)) => {
+                                metrics.record_search(search_started.elapsed());
+                                DaemonResponse::SearchResults(response)
+                            }
+                            Ok(None) => DaemonResponse::SearchCancelled,
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    DaemonRequest::SearchStream(request) => {
+                        let search_started = Instant::now();
+                        let searched = on_index(&file_index, move |index| {
+                            index.search_cancellable(&request, &token)
+                        })
+                        .await
+                        .and_then(|searched| searched);
+                        let Ok(Some(response)) = searched else {
+                            let response = match searched {
+                                Err(e) => DaemonResponse::Error {
+                                    message: e.to_string(),
+                                },
+                                _ => DaemonResponse::SearchCancelled,
+                            };
+                            if let Err(e) = send_response(
+                                &response,
+                                framing,
+                                compress_above,
+                                &connection,
+                                &mut subscriber,
+                                &mut fallback_writer,
+                            )
+                            .await
+                            {
+                                warn!("Failed to write fallback response: {}", e);
+                                return ControlFlow::Break(());
+                            }
+                            return ControlFlow::Continue(());
+                        };
+                        metrics.record_search(search_started.elapsed());
+                        if let Err(e) = stream_search(
+                            response,
+                            framing,
+                            compress_above,
+                            &connection,
+                            &mut subscriber,
+                            &mut fallback_writer,
+                        )
+                        .await
+                        {
+                            warn!("Failed to stream search results: {}", e);
+                            return ControlFlow::Break(());
+                        }
+                        return ControlFlow::Continue(());
+                    }
+                    DaemonRequest::Refresh { .. } => {
+                        match rescan(Arc::clone(&file_index), refresh_timeout).await {
+                            Ok(response) => {
+                                metrics.record_refresh();
+                                response
+                            }
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    DaemonRequest::ScanPreview { roots, exclude } => {
+                        match scan_preview(&file_index, roots, &exclude, refresh_timeout).await {
+                            Ok(response) => response,
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    DaemonRequest::RefreshPath { path } => {
+                        match rescan_subtree(
+                            Arc::clone(&file_index),
+                            PathBuf::from(path),
+                            refresh_timeout,
+                        )
+                        .await
+                        {
+                            Ok(response) => {
+                                metrics.record_refresh();
+                                response
+                            }
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    DaemonRequest::Status { .. } => {
+                        let index = file_index.read().await;
+                        DaemonResponse::Status {
+                            files_count: index.len(),
+                            last_updated: index.last_updated_timestamp(),
+                            scanner: index.scanner_name().to_string(),
+                            index_bytes: index.memory_usage(),
+                            last_scan_duration_ms: index
+                                .last_scan_duration()
+                                .map(|took| took.as_millis() as u64),
+                            roots: index.root_counts(),
+                            active_clients: connections.len(),
+                            index_capped: index.is_capped(),
+                            stale: index.is_stale(),
+                            root_errors: index.root_errors().to_vec(),
+                            root_warnings: index.root_warnings().to_vec(),
+                        }
+                    }
+                    DaemonRequest::SetRefreshInterval { secs } => {
+                        refresh_interval.send_replace(Duration::from_secs(secs));
+                        ctx.settings
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .refresh_secs = secs;
+                        info!("Refresh interval set to {}s", secs);
+                        DaemonResponse::RefreshIntervalSet { secs }
+                    }
+                    DaemonRequest::RecordSelection { path } => {
+                        let mut index = file_index.write().await;
+                        match index.record_selection(&path) {
+                            Ok(()) => DaemonResponse::SelectionRecorded,
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    // The daemon would connect out to whatever socket the
+                    // client names, so only local clients may pick one, and
+                    // only among the daemon's own sockets.
+                    DaemonRequest::Subscribe { .. } if matches!(peer, Peer::Tcp(_)) => {
+                        DaemonResponse::Error {
+                            message: "Subscribe is not available over TCP".to_string(),
+                        }
+                    }
+                    DaemonRequest::Subscribe { response_socket } => {
+                        let path =
+                            response_socket.unwrap_or_else(|| default_response_socket.clone());
+                        if !in_socket_dir(&path, &default_response_socket) {
+                            DaemonResponse::Error {
+                                message: format!(
+                                    "Response socket {} is outside the daemon's socket directory",
+                                    path.display()
+                                ),
+                            }
+                        } else {
+                            match ResponseSubscriber::connect(path.clone()).await {
+                            Ok(subscribed) => {
+                                info!("Client subscribed response socket {}", path.display());
+                                subscriber = Some(subscribed);
+                                DaemonResponse::Subscribed
+                            }
+                            Err(e) => DaemonResponse::Error {
+                                message: format!(
+                                    "Failed to connect to response socket {}: {}",
+                                    path.display(),
+                                    e
+                                ),
+                            },
+                        }
+                        }
+                    }
+                    DaemonRequest::ClearHistory => match file_index.write().await.clear_history() {
+                        Ok(entries_removed) => DaemonResponse::HistoryCleared { entries_removed },
+                        Err(e) => DaemonResponse::Error {
+                            message: e.to_string(),
+                        },
+                    },
+                    DaemonRequest::Recent { limit } => {
+                        match file_index.read().await.recent(limit) {
+                            Ok(response) => DaemonResponse::SearchResults(response),
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    DaemonRequest::Lookup { path } => match file_index.read().await.lookup(&path) {
+                        Ok(result) => DaemonResponse::SearchResult(result),
+                        Err(e) => DaemonResponse::Error {
+                            message: e.to_string(),
+                        },
+                    },
+                    DaemonRequest::Suggest { query, limit } => DaemonResponse::Suggestions {
+                        suggestions: file_index.read().await.suggest(&query, limit),
+                    },
+                    #[cfg(unix)]
+                    DaemonRequest::Duplicates => match file_index.read().await.duplicates() {
+                        Ok(groups) => DaemonResponse::Duplicates { groups },
+                        Err(e) => DaemonResponse::Error {
+                            message: e.to_string(),
+                        },
+                    },
+                    DaemonRequest::ListFiles { offset, limit } => {
+                        file_index.read().await.list_files(offset, limit)
+                    }
+                    DaemonRequest::Connections => DaemonResponse::Connections {
+                        connections: connections.list(),
+                    },
+                    DaemonRequest::GetConfig => {
+                        let scanner = file_index.read().await.scanner_name().to_string();
+                        let config = ctx
+                            .settings
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .clone();
+                        DaemonResponse::Config { config, scanner }
+                    }
+                    DaemonRequest::ReloadConfig => match reload_config(
+                        &file_index,
+                        &namespaces,
+                        &refresh_interval,
+                        &metrics,
+                        &ctx.args,
+                        &ctx.settings,
+                    )
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(e) => DaemonResponse::Error {
+                            message: format!("{:#}", e),
+                        },
+                    },
+                    DaemonRequest::Shutdown if ctx.allow_shutdown_request => {
+                        info!("Shutdown requested by client");
+                        stop_after_reply = true;
+                        DaemonResponse::ShuttingDown
+                    }
+                    DaemonRequest::Shutdown => DaemonResponse::Error {
+                        message: "Shutdown requests are disabled".to_string(),
+                    },
+                    DaemonRequest::Metrics => DaemonResponse::Metrics {
+                        searches_total: metrics.searches(),
+                        refreshes_total: metrics.refreshes(),
+                        avg_search_ms: metrics.avg_search_ms(),
+                        files_count: file_index.read().await.len(),
+                        active_clients: connections.len(),
+                        uptime_secs: started.elapsed().as_secs(),
+                    },
+                    DaemonRequest::Hello { .. } if request_number > 1 => DaemonResponse::Error {
+                        message: "Hello must be the first request on a connection".to_string(),
+                    },
+                    DaemonRequest::Hello {
+                        client_version,
+                        protocol_version,
+                        compression,
+                    } => {
+                        if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+                            debug!(
+                                "Client {} speaks protocol {}",
+                                client_version.as_deref().unwrap_or("(unnamed)"),
+                                protocol_version
+                            );
+                            // The Hello reply itself still goes out without
+                            // a flag byte.
+                            compress_after_reply = compression && framing.carries_binary();
+                            DaemonResponse::Hello {
+                                daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+                                protocol_version,
+                                min_protocol_version: MIN_PROTOCOL_VERSION,
+                                max_protocol_version: PROTOCOL_VERSION,
+                                compression: compress_after_reply,
+                            }
+                        } else {
+                            info!(
+                                "Rejecting client {} with protocol {}",
+                                client_version.as_deref().unwrap_or("(unnamed)"),
+                                protocol_version
+                            );
+                            close_after_reply = true;
+                            DaemonResponse::Error {
+                                message: format!(
+                                    "Unsupported protocol version {}; this daemon supports {} to {}",
+                                    protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                                ),
+                            }
+                        }
+                    }
+                    DaemonRequest::Ping => DaemonResponse::Pong {
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        uptime_secs: started.elapsed().as_secs(),
+                    },
+                    DaemonRequest::Warmup => {
+                        match on_index(&file_index, |index| (index.warmup(), index.len())).await {
+                            Ok((took, files_count)) => {
+                                debug!("Warmed up in {:?}", took);
+                                DaemonResponse::WarmedUp {
+                                    files_count,
+                                    took_ms: took.as_millis() as u64,
+                                }
+                            }
+                            Err(e) => DaemonResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                },
+                Err(e) => DaemonResponse::Error {
+                    message: format!("Invalid request: {}", e),
+                },
+            };
+
+            if let Err(e) = send_response(
+                &response,
+                framing,
+                compress_above,
+                &connection,
+                &mut subscriber,
+                &mut fallback_writer,
+            )
+            .await
+            {
+                warn!("Failed to write fallback response: {}", e);
+                return ControlFlow::Break(());
+            }
+            if stop_after_reply {
+                ctx.shutdown.send_replace(true);
+            }
+            if compress_after_reply {
+                compress_above = Some(ctx.args.compress_min_bytes);
+            }
+            if close_after_reply {
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        }
+        .instrument(span)
+        .await;
+        if flow.is_break() {
+            break;
         }
     }
 
-    pub fn update(&mut self) -> Result<()> {
-        info!("Updating file index...");
+    reader_task.abort();
+    connections.close(&connection);
+    debug!("Client disconnected. Active clients: {}", connections.len());
+    Ok(())
+}
 
-        let home = std::env::var("HOME").unwrap_or_else(|_| "/home".to_string());
+/// Runs `work` under a read lock on the blocking pool. Scoring a large index
+/// keeps a thread busy for the whole search, which must not be one of the
+/// runtime's workers: those also accept clients and read cancellations.
+async fn on_index<T, F>(file_index: &Arc<RwLock<FileIndex>>, work: F) -> Result<T>
+where
+    F: FnOnce(&FileIndex) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let index = Arc::clone(file_index).read_owned().await;
+    tokio::task::spawn_blocking(move || work(&index))
+        .await
+        .context("Index task failed")
+}
 
-        let output = Command::new("fd")
-            .args([".", &home, "--type", "file"])
-            .output()?;
+/// Reads requests ahead of the handler. Every search bumps `latest_search`
+/// on arrival, so a search still being scored notices it has been superseded
+/// without waiting for its turn.
+async fn read_requests<R>(
+    reader: R,
+    max_request_bytes: usize,
+    latest_search: Arc<AtomicU64>,
+    requests: mpsc::Sender<(Result<DaemonRequest>, SearchToken, Framing)>,
+) where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    loop {
+        let (framing, frame) = match framing::read_frame(&mut reader, max_request_bytes).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                match e.get_ref().and_then(|e| e.downcast_ref::<FrameTooLarge>()) {
+                    Some(too_large) => {
+                        warn!("{}, closing the connection", too_large);
+                        // Answer in the framing the client used, then hang up.
+                        let token = SearchToken::new(Arc::clone(&latest_search), 0);
+                        let error = Err(anyhow::anyhow!("{}", too_large));
+                        let _ = requests.send((error, token, too_large.framing)).await;
+                    }
+                    None => warn!("Failed to read request: {}", e),
+                }
+                break;
+            }
+        };
+        debug!("Received request: {}", framing.display(&frame));
 
-        if !output.status.success() {
-            anyhow::bail!(
-                "fd command failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+        let request = framing.deserialize::<DaemonRequest>(&frame);
+        let id = match request {
+            Ok(DaemonRequest::Search(_) | DaemonRequest::SearchStream(_)) => {
+                latest_search.fetch_add(1, Ordering::Relaxed) + 1
+            }
+            _ => latest_search.load(Ordering::Relaxed),
+        };
+        let token = SearchToken::new(Arc::clone(&latest_search), id);
+        if requests.send((request, token, framing)).await.is_err() {
+            break;
         }
+    }
+}
 
-        let stdout = String::from_utf8(output.stdout)?;
-        self.files = stdout
-            .lines()
-            .filter(|line| !line.trim().is_empty())
-            .map(|path| {
-                let display_path = if path.starts_with(&home) {
-                    format!("~{}", &path[home.len()..])
-                } else {
-                    path.to_string()
-                };
-
-                FileEntry {
-                    path: path.to_string(),
-                    display_path,
-                }
-            })
-            .collect();
-
-        self.last_updated = std::time::SystemTime::now();
-        info!("Indexed {} files", self.files.len());
-        Ok(())
-    }
-
-    pub fn search(&mut self, query: &str, limit: Option<usize>) -> Vec<SearchResult> {
-        let limit = limit.unwrap_or(100);
-
-        if query.is_empty() {
-            return self
-                .files
-                .iter()
-                .take(limit)
-                .map(|file| SearchResult {
-                    path: file.path.clone(),
-                    display_path: file.display_path.clone(),
-                    matches: Vec::new(),
-                    score: 0,
-                })
-                .collect();
+/// Writes a whole frame, retrying transient errors with a short backoff so
+/// a slow reader does not lose its connection. Other errors, such as a
+/// broken pipe, are returned at once.
+async fn write_frame<W>(writer: &mut W, frame: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    let mut retries = 0;
+    let mut delay = WRITE_RETRY_DELAY;
+    loop {
+        let result = if written < frame.len() {
+            writer.write(&frame[written..]).await
+        } else {
+            writer.flush().await.map(|()| 0)
+        };
+        match result {
+            Ok(0) if written < frame.len() => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) if written < frame.len() => written += n,
+            Ok(_) => return Ok(()),
+            Err(e) if is_transient(&e) && retries < WRITE_RETRIES => {
+                debug!("Retrying write after {:?}: {}", delay, e);
+                retries += 1;
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
         }
+    }
+}
 
-        let mut results = Vec::new();
-
-        let pattern = Pattern::parse(query, CaseMatching::Ignore, Normalization::Smart);
-
-        for file in &self.files {
-            let filename = Path::new(&file.display_path)
-                .file_name()
-                .and_then(|name| name.to_str())
-                .unwrap_or("");
-
-            let mut haystack_vec = Vec::new();
-            let haystack = Utf32Str::new(filename, &mut haystack_vec);
-
-            if let Some(score) = pattern.score(haystack, &mut self.matcher) {
-                let mut indices = Vec::new();
-                pattern.indices(haystack, &mut self.matcher, &mut indices);
-
-                let filename_offset = if let Some(last_slash_pos) = file.display_path.rfind('/') {
-                    last_slash_pos + 1
-                } else {
-                    0
-                };
-
-                let matches = indices
-                    .into_iter()
-                    .map(|idx| SearchMatch {
-                        char_index: idx + filename_offset as u32,
-                    })
-                    .collect();
-
-                results.push(SearchResult {
-                    path: file.path.clone(),
-                    display_path: file.display_path.clone(),
-                    matches,
-                    score: score as i32,
-                });
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Writes one response to the client's subscribed response socket, falling
+/// back to the connection the request arrived on while the socket is
+/// unreachable. A subscriber that keeps failing is dropped. Only a failed
+/// fallback write is reported as an error. With `compress_above` set, the
+/// payload starts with a flag byte and is gzipped when larger than that.
+async fn send_response<W>(
+    response: &DaemonResponse,
+    framing: Framing,
+    compress_above: Option<usize>,
+    connection: &ConnectionStats,
+    subscriber: &mut Option<ResponseSubscriber>,
+    fallback_writer: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let payload = framing.serialize(response)?;
+    let frame = match compress_above {
+        Some(min_bytes) if framing.carries_binary() => {
+            framing.encode(&framing::compress(&payload, min_bytes)?)
+        }
+        _ => framing.encode(&payload),
+    };
+
+    if let Some(target) = subscriber {
+        if let Some(writer) = target.stream().await {
+            match write_frame(writer, &frame).await {
+                Ok(()) => {
+                    target.record_success();
+                    connection.record_written(frame.len());
+                    debug!(
+                        "Sent response via response socket: {}",
+                        framing.display(&payload)
+                    );
+                    return Ok(());
+                }
+                Err(e) => target.record_failure(&e),
             }
         }
+        if target.gave_up() {
+            *subscriber = None;
+        }
+    }
 
-        results.sort_by(|a, b| b.score.cmp(&a.score));
-        results.truncate(limit);
-        results
+    write_frame(fallback_writer, &frame).await?;
+    connection.record_written(frame.len());
+    debug!(
+        "Sent response via request socket (fallback): {}",
+        framing.display(&payload)
+    );
+    Ok(())
+}
+
+/// Sends each result as its own message as soon as it is serialized, then a
+/// `SearchEnd` message carrying the totals.
+async fn stream_search<W>(
+    response: SearchResponse,
+    framing: Framing,
+    compress_above: Option<usize>,
+    connection: &ConnectionStats,
+    subscriber: &mut Option<ResponseSubscriber>,
+    fallback_writer: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let end = DaemonResponse::SearchEnd {
+        results_count: response.results_count,
+        total_files: response.total_files,
+        has_more: response.has_more,
+        limit_capped: response.limit_capped,
+        took_ms: response.took_ms,
+        scored_files: response.scored_files,
+        skipped_files: response.skipped_files,
+        timed_out: response.timed_out,
+    };
+    for result in response.results {
+        let line = DaemonResponse::SearchResult(result);
+        send_response(
+            &line,
+            framing,
+            compress_above,
+            connection,
+            subscriber,
+            fallback_writer,
+        )
+        .await?;
     }
+    send_response(
+        &end,
+        framing,
+        compress_above,
+        connection,
+        subscriber,
+        fallback_writer,
+    )
+    .await
+}
+
+fn get_user_id() -> Result<String> {
+    let output = Command::new("id").args(["-u"]).output()?;
 
-    pub fn len(&self) -> usize {
-        self.files.len()
+    if !output.status.success() {
+        anyhow::bail!(
+            "id command failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.files.is_empty()
+    let uid = String::from_utf8(output.stdout)?.trim().to_string();
+    Ok(uid)
+}
+
+/// Directory holding both sockets: `$XDG_RUNTIME_DIR/qs-daemon`, or
+/// `/tmp/qs-daemon-$UID` when no runtime directory is set.
+fn default_socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) if !runtime_dir.is_empty() => {
+            PathBuf::from(runtime_dir).join("qs-daemon")
+        }
+        _ => {
+            let uid = get_user_id().unwrap_or_else(|_| "1000".to_string());
+            PathBuf::from(format!("/tmp/qs-daemon-{}", uid))
+        }
     }
+}
+
+/// Whether `path` lies in the directory of the default response socket or
+/// in the default socket directory, once symlinks and `..` are resolved. A
+/// socket that does not exist is not in either.
+fn in_socket_dir(path: &Path, default_response_socket: &Path) -> bool {
+    let Ok(path) = std::fs::canonicalize(path) else {
+        return false;
+    };
+    [
+        default_response_socket.parent().map(Path::to_path_buf),
+        Some(default_socket_dir()),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|dir| std::fs::canonicalize(dir).ok())
+    .any(|dir| path.starts_with(dir))
+}
 
-    pub fn last_updated_timestamp(&self) -> u64 {
-        self.last_updated
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
+/// Creates the directory a socket lives in, accessible only by the owner.
+/// Existing directories are left untouched.
+fn prepare_socket_dir(socket_path: &Path) -> Result<()> {
+    match socket_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+            Ok(())
+        }
+        _ => Ok(()),
     }
 }
 
-async fn handle_client(
-    mut stream: UnixStream,
-    file_index: Arc<Mutex<FileIndex>>,
-    response_writer: Arc<Mutex<Option<UnixStream>>>,
-    active_clients: Arc<AtomicUsize>,
-) -> Result<()> {
-    active_clients.fetch_add(1, Ordering::Relaxed);
-    debug!(
-        "Client connected. Active clients: {}",
-        active_clients.load(Ordering::Relaxed)
-    );
-    let (reader, mut fallback_writer) = stream.split();
-    let mut lines = BufReader::new(reader).lines();
-
-    while let Some(line) = lines.next_line().await? {
-        debug!("Received request: {}", line);
-
-        let response = match serde_json::from_str::<DaemonRequest>(&line) {
-            Ok(request) => match request {
-                DaemonRequest::Search { query, limit } => {
-                    let mut index = file_index.lock().unwrap();
-                    let results = index.search(&query, limit);
-                    let results_count = results.len();
-                    let total_files = index.len();
-                    DaemonResponse::SearchResults(SearchResponse {
-                        results,
-                        results_count,
-                        total_files,
-                    })
-                }
-                DaemonRequest::Refresh => {
-                    let mut index = file_index.lock().unwrap();
-                    match index.update() {
-                        Ok(()) => DaemonResponse::RefreshComplete {
-                            files_count: index.len(),
-                        },
-                        Err(e) => DaemonResponse::Error {
-                            message: e.to_string(),
-                        },
-                    }
-                }
-                DaemonRequest::Status => {
-                    let index = file_index.lock().unwrap();
-                    DaemonResponse::Status {
-                        files_count: index.len(),
-                        last_updated: index.last_updated_timestamp(),
-                    }
-                }
-            },
-            Err(e) => DaemonResponse::Error {
-                message: format!("Invalid request: {}", e),
-            },
-        };
+/// Removes a socket file that nothing is listening on anymore. The response
+/// socket belongs to the GUI, so it is left alone while the GUI is up.
+fn remove_stale_socket(path: &Path) {
+    if !path.exists() || std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return;
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => info!("Removed stale socket {}", path.display()),
+        Err(e) => warn!("Failed to remove socket {}: {}", path.display(), e),
+    }
+}
 
-        let response_json = serde_json::to_string(&response)?;
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("Failed to install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
 
-        let mut sent_via_response_socket = false;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+    }
+}
 
-        let response_writer_option = {
-            let mut response_writer_guard = response_writer.lock().unwrap();
-            response_writer_guard.take()
-        };
+/// Rescans every index on each SIGUSR1. Signals that arrive during a rescan
+/// are folded into a single rescan after it, so a burst never runs more
+/// than one at a time.
+async fn refresh_on_signal(
+    indexes: Vec<Arc<RwLock<FileIndex>>>,
+    metrics: Arc<Metrics>,
+    timeout: Option<Duration>,
+) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            error!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        info!("Received SIGUSR1, refreshing");
+        for file_index in &indexes {
+            match rescan(Arc::clone(file_index), timeout).await {
+                Ok(_) => metrics.record_refresh(),
+                Err(e) => error!("Refresh on SIGUSR1 failed: {}", e),
+            }
+        }
+    }
+}
 
-        if let Some(mut writer) = response_writer_option {
-            let send_result = async {
-                writer.write_all(response_json.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-                writer.flush().await?;
-                Ok::<_, std::io::Error>(writer)
+/// Serves `stream` on its own task, tracked in `clients` so shutdown can
+/// wait for it. Beyond `--max-clients`, the connection is turned away.
+fn spawn_client<S>(clients: &mut JoinSet<()>, stream: S, peer: Peer, ctx: &ServerContext)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Ok(slot) = Arc::clone(&ctx.client_slots).try_acquire_owned() else {
+        warn!("Rejecting client {}: too many clients", peer);
+        clients.spawn(reject_client(stream));
+        return;
+    };
+    let ctx = ctx.clone();
+    clients.spawn(async move {
+        if let Err(e) = handle_client(stream, peer, ctx).await {
+            warn!("Client handler error: {}", e);
+        }
+        drop(slot);
+    });
+}
+
+/// Answers a connection over the `--max-clients` limit with an error and
+/// closes it.
+async fn reject_client<S>(mut stream: S)
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = DaemonResponse::Error {
+        message: "too many clients".to_string(),
+    };
+    let reply = async {
+        let payload = Framing::Lines.serialize(&response)?;
+        write_frame(&mut stream, &Framing::Lines.encode(&payload)).await?;
+        stream.shutdown().await
+    };
+    match tokio::time::timeout(REJECT_TIMEOUT, reply).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to tell client it was rejected: {}", e),
+        Err(_) => debug!("Timed out telling client it was rejected"),
+    }
+}
+
+/// Accepts from the optional TCP listener, or never completes without one.
+async fn accept_tcp(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn start_socket_server(
+    socket_path: PathBuf,
+    tcp_addr: Option<SocketAddr>,
+    ctx: ServerContext,
+) -> Result<()> {
+    let mut shutdown = ctx.shutdown.subscribe();
+    // A socket passed in by systemd belongs to systemd, which keeps it
+    // around to start the daemon again; only a socket we bound is removed.
+    let (listener, owned_socket) = match systemd::inherited_listener()? {
+        Some(listener) => (UnixListener::from_std(listener)?, None),
+        None => {
+            prepare_socket_dir(&socket_path)?;
+            if socket_path.exists() {
+                std::fs::remove_file(&socket_path)?;
             }
-            .await;
-
-            match send_result {
-                Ok(writer) => {
-                    debug!("Sent response via response socket: {}", response_json);
-                    sent_via_response_socket = true;
-                    let mut response_writer_guard = response_writer.lock().unwrap();
-                    *response_writer_guard = Some(writer);
-                }
-                Err(e) => {
-                    warn!("Failed to send via response socket: {}", e);
-                }
+            let listener = UnixListener::bind(&socket_path)?;
+            info!("Request server listening on {}", socket_path.display());
+            (listener, Some(socket_path))
+        }
+    };
+
+    let tcp_listener = match tcp_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            if !addr.ip().is_loopback() {
+                warn!(
+                    "Listening on {} without authentication; anyone who can reach it can query the index",
+                    addr
+                );
             }
+            info!(
+                "Request server listening on tcp://{}",
+                listener.local_addr()?
+            );
+            Some(listener)
         }
+        None => None,
+    };
 
-        if !sent_via_response_socket {
-            match fallback_writer.write_all(response_json.as_bytes()).await {
-                Ok(_) => match fallback_writer.write_all(b"\n").await {
-                    Ok(_) => match fallback_writer.flush().await {
-                        Ok(_) => {
-                            debug!(
-                                "Sent response via request socket (fallback): {}",
-                                response_json
-                            );
-                        }
-                        Err(e) => {
-                            warn!("Failed to flush fallback response: {}", e);
-                            break;
-                        }
-                    },
-                    Err(e) => {
-                        warn!("Failed to write newline to fallback: {}", e);
-                        break;
-                    }
-                },
+    let mut clients = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = accept_tcp(tcp_listener.as_ref()) => match accepted {
+                Ok((stream, addr)) => spawn_client(&mut clients, stream, Peer::Tcp(addr), &ctx),
                 Err(e) => {
-                    warn!("Failed to write fallback response: {}", e);
-                    break;
+                    error!("Failed to accept TCP connection: {}", e);
                 }
-            }
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _addr)) => {
+                    spawn_client(&mut clients, stream, Peer::Unix, &ctx)
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                }
+            },
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            _ = shutdown.wait_for(|stop| *stop) => break,
         }
     }
 
-    active_clients.fetch_sub(1, Ordering::Relaxed);
-    debug!(
-        "Client disconnected. Active clients: {}",
-        active_clients.load(Ordering::Relaxed)
-    );
+    drop(listener);
+    drop(tcp_listener);
+    if let Some(socket_path) = owned_socket
+        && let Err(e) = std::fs::remove_file(&socket_path)
+    {
+        warn!("Failed to remove socket {}: {}", socket_path.display(), e);
+    }
+
+    info!("Waiting for {} client(s) to finish", clients.len());
+    let drain = async { while clients.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_GRACE, drain).await.is_err() {
+        warn!("Clients did not finish in time, closing remaining connections");
+        clients.abort_all();
+    }
+
     Ok(())
 }
 
-fn get_user_id() -> Result<String> {
-    let output = Command::new("id")
-        .args(["-u"])
-        .output()?;
-    
-    if !output.status.success() {
-        anyhow::bail!("id command failed: {}", String::from_utf8_lossy(&output.stderr));
-    }
-    
-    let uid = String::from_utf8(output.stdout)?.trim().to_string();
-    Ok(uid)
+/// Scans and sorts the new file list on a blocking thread without holding
+/// the index lock, then swaps it in under a brief write lock, so searches
+/// keep running against the old list until the new one is ready. The cache
+/// is written afterwards under a read lock. Returns the new file count.
+async fn rescan(
+    file_index: Arc<RwLock<FileIndex>>,
+    timeout: Option<Duration>,
+) -> Result<DaemonResponse> {
+    let (mut scan, sorted) = {
+        let index = file_index.read().await;
+        (index.scan_config(), index.has_name_index())
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    scan.options.cancel = Some(Arc::clone(&cancel));
+    let scanning = tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let (files, problems) = scan.scan()?;
+        anyhow::Ok(
+            PreparedFiles::new(files, problems, sorted).with_scan_duration(started.elapsed()),
+        )
+    });
+    let prepared = finish_scan(scanning, timeout, &cancel).await?;
+    let response = {
+        let mut index = file_index.write().await;
+        index.swap_files(prepared);
+        index.refresh_complete()
+    };
+    tokio::task::spawn_blocking(move || file_index.blocking_read().save_cache()).await?;
+    Ok(response)
 }
 
-async fn start_socket_server(
-    file_index: Arc<Mutex<FileIndex>>,
-    response_writer: Arc<Mutex<Option<UnixStream>>>,
-    active_clients: Arc<AtomicUsize>,
-) -> Result<()> {
-    let uid = get_user_id().unwrap_or_else(|_| "1000".to_string());
-    let socket_path = format!("/run/user/{}/quickfile-daemon.sock", uid);
+/// Like [`rescan`], but only for `dir`. Returns the new total file count.
+async fn rescan_subtree(
+    file_index: Arc<RwLock<FileIndex>>,
+    dir: PathBuf,
+    timeout: Option<Duration>,
+) -> Result<DaemonResponse> {
+    let mut scan = file_index.read().await.scan_config();
+    let cancel = Arc::new(AtomicBool::new(false));
+    scan.options.cancel = Some(Arc::clone(&cancel));
+    let scanning = tokio::task::spawn_blocking(move || {
+        let files = scan.scan_subtree(&dir)?;
+        anyhow::Ok((dir, files))
+    });
+    let (dir, files) = finish_scan(scanning, timeout, &cancel).await?;
+    let response = tokio::task::spawn_blocking(move || {
+        let mut index = file_index.blocking_write();
+        index.replace_subtree(&dir, files);
+        index.refresh_complete()
+    })
+    .await?;
+    Ok(response)
+}
 
-    if std::path::Path::new(&socket_path).exists() {
-        std::fs::remove_file(&socket_path)?;
+/// Display paths a `ScanPreview` reply lists.
+const PREVIEW_SAMPLE: usize = 10;
+
+/// Scans `roots` with the index's settings but `exclude` as its excludes,
+/// and reports the result without touching the index.
+async fn scan_preview(
+    file_index: &RwLock<FileIndex>,
+    roots: Vec<String>,
+    exclude: &[String],
+    timeout: Option<Duration>,
+) -> Result<DaemonResponse> {
+    if roots.is_empty() {
+        anyhow::bail!("ScanPreview needs at least one root");
+    }
+    let mut scan = file_index.read().await.scan_config();
+    scan.roots = roots
+        .into_iter()
+        .map(|root| {
+            let root = PathBuf::from(root);
+            std::path::absolute(&root).unwrap_or(root)
+        })
+        .collect();
+    scan.options.exclude = ExcludeMatcher::new(exclude)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    scan.options.cancel = Some(Arc::clone(&cancel));
+    let scanning = tokio::task::spawn_blocking(move || scan.scan());
+    let (files, problems) = finish_scan(scanning, timeout, &cancel).await?;
+    let mut sample: Vec<&str> = files
+        .iter()
+        .map(|file| file.display_path.as_str())
+        .collect();
+    if sample.len() > PREVIEW_SAMPLE {
+        sample.select_nth_unstable(PREVIEW_SAMPLE - 1);
+        sample.truncate(PREVIEW_SAMPLE);
     }
+    sample.sort_unstable();
+    Ok(DaemonResponse::ScanPreview {
+        files_count: files.len(),
+        sample: sample.into_iter().map(str::to_string).collect(),
+        root_errors: problems.errors,
+        root_warnings: problems.warnings,
+    })
+}
 
-    let listener = UnixListener::bind(&socket_path)?;
-    info!("Request server listening on {}", socket_path);
+/// Waits for a scan running on the blocking pool. Once `timeout` passes the
+/// scan is told to stop and the refresh fails, leaving the index as it was.
+async fn finish_scan<T>(
+    scanning: JoinHandle<Result<T>>,
+    timeout: Option<Duration>,
+    cancel: &AtomicBool,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return scanning.await?;
+    };
+    match tokio::time::timeout(timeout, scanning).await {
+        Ok(scanned) => scanned?,
+        Err(_) => {
+            cancel.store(true, Ordering::Relaxed);
+            warn!(
+                "Refresh took longer than {}s, cancelling it",
+                timeout.as_secs()
+            );
+            anyhow::bail!("refresh timed out")
+        }
+    }
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _addr)) => {
-                let file_index = Arc::clone(&file_index);
-                let response_writer = Arc::clone(&response_writer);
-                let active_clients = Arc::clone(&active_clients);
-                tokio::spawn(async move {
-                    if let Err(e) =
-                        handle_client(stream, file_index, response_writer, active_clients).await
-                    {
-                        warn!("Client handler error: {}", e);
-                    }
-                });
-            }
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
+/// Re-reads the config file and applies the roots and filters (rescanning
+/// in the background), the refresh interval and the match settings. The
+/// file is validated first, so a bad edit leaves the daemon as it was.
+async fn reload_config(
+    file_index: &Arc<RwLock<FileIndex>>,
+    namespaces: &BTreeMap<String, Namespace>,
+    refresh_interval: &watch::Sender<Duration>,
+    metrics: &Arc<Metrics>,
+    args: &Args,
+    settings: &Mutex<Settings>,
+) -> Result<DaemonResponse> {
+    let file = config::Config::default_path();
+    let mut config = config::Config::read(&file)?;
+    let namespace_configs = std::mem::take(&mut config.namespaces);
+    let mut new = resolve_settings(args, config);
+    let exclude = ExcludeMatcher::new(&new.exclude).context("Invalid exclude pattern")?;
+    let include = IncludeMatcher::new(&new.include).context("Invalid include pattern")?;
+    let old = settings
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+
+    // The sockets are already bound, so keep reporting the ones in use.
+    let mut restart_required = Vec::new();
+    if new.request_socket != old.request_socket {
+        restart_required.push("request_socket".to_string());
+        new.request_socket = old.request_socket.clone();
+    }
+    if new.response_socket != old.response_socket {
+        restart_required.push("response_socket".to_string());
+        new.response_socket = old.response_socket.clone();
+    }
+    if new.stream_command != old.stream_command {
+        restart_required.push("stream_command".to_string());
+        new.stream_command = old.stream_command.clone();
+    }
+    // The watcher keeps watching the roots it started with, so indexing
+    // new ones would leave them without live updates.
+    if new.roots != old.roots && args.watching() {
+        restart_required.push("roots".to_string());
+        new.roots = old.roots.clone();
+    }
+    let namespaces_changed = !namespace_configs.iter().eq(namespaces
+        .iter()
+        .map(|(name, namespace)| (name, &namespace.config)));
+    if namespaces_changed {
+        restart_required.push("namespaces".to_string());
+    }
+
+    let rescanning = new.roots != old.roots
+        || new.exclude != old.exclude
+        || new.include != old.include
+        || new.path_aliases != old.path_aliases;
+    file_index.write().await.reconfigure(&new, exclude, include);
+    if new.refresh_secs != old.refresh_secs {
+        refresh_interval.send_replace(Duration::from_secs(new.refresh_secs));
+    }
+    *settings.lock().unwrap_or_else(PoisonError::into_inner) = new.clone();
+    info!("Reloaded config from {}", file.display());
+
+    if rescanning {
+        let file_index = Arc::clone(file_index);
+        let metrics = Arc::clone(metrics);
+        let timeout = args.refresh_timeout();
+        tokio::spawn(async move {
+            match rescan(file_index, timeout).await {
+                Ok(_) => metrics.record_refresh(),
+                Err(e) => error!("Refresh after config reload failed: {}", e),
             }
-        }
+        });
     }
+
+    Ok(DaemonResponse::ConfigReloaded {
+        config: new,
+        restart_required,
+        rescanning,
+    })
 }
 
-async fn manage_response_connection(
-    response_writer: Arc<Mutex<Option<UnixStream>>>,
-    active_clients: Arc<AtomicUsize>,
+/// Rescans on a fixed cadence. The cadence can be changed at runtime through
+/// `refresh_interval`, which restarts the timer; a zero duration pauses
+/// periodic refreshes until a non-zero value arrives. A tick that comes
+/// less than a period after another full scan (such as a client's
+/// `Refresh`) is skipped and the next one is due a period after that scan.
+async fn periodic_refresh(
+    file_index: Arc<RwLock<FileIndex>>,
+    mut refresh_interval: watch::Receiver<Duration>,
+    metrics: Arc<Metrics>,
+    timeout: Option<Duration>,
 ) {
-    let uid = get_user_id().unwrap_or_else(|_| "1000".to_string());
-    let response_socket_path = format!("/run/user/{}/quickfile-response.sock", uid);
-
     loop {
-        let has_active_clients = active_clients.load(Ordering::Relaxed) > 0;
+        let period = *refresh_interval.borrow_and_update();
 
-        if !has_active_clients {
-            {
-                let mut writer_guard = response_writer.lock().unwrap();
-                if writer_guard.is_some() {
-                    debug!("No active clients, disconnecting from response server");
-                    *writer_guard = None;
-                }
+        if period.is_zero() {
+            info!("Periodic refresh disabled");
+            if refresh_interval.changed().await.is_err() {
+                return;
             }
-            sleep(Duration::from_millis(1000)).await;
             continue;
         }
 
-        let needs_connection = {
-            let writer_guard = response_writer.lock().unwrap();
-            writer_guard.is_none()
-        };
-
-        if !needs_connection {
-            sleep(Duration::from_millis(1000)).await;
-            continue;
-        }
-
-        info!(
-            "Attempting to connect to response server at {} (active clients: {})",
-            response_socket_path,
-            active_clients.load(Ordering::Relaxed)
-        );
+        let mut next = tokio::time::Instant::now() + period;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => {
+                    let since_refresh = file_index.read().await.since_refresh();
+                    if let Some(since_refresh) = since_refresh.filter(|since| *since < period) {
+                        let remaining = period - since_refresh;
+                        debug!(
+                            "Skipping periodic refresh, the index was refreshed {}s ago; next in {}s",
+                            since_refresh.as_secs(),
+                            remaining.as_secs()
+                        );
+                        next = tokio::time::Instant::now() + remaining;
+                        continue;
+                    }
+                    info!("Performing periodic file index refresh...");
 
-        match UnixStream::connect(&response_socket_path).await {
-            Ok(stream) => {
-                info!("Connected to response server");
-                {
-                    let mut writer_guard = response_writer.lock().unwrap();
-                    *writer_guard = Some(stream);
+                    match rescan(Arc::clone(&file_index), timeout).await {
+                        Ok(_) => metrics.record_refresh(),
+                        Err(e) => error!("Periodic refresh failed: {}", e),
+                    }
+                    next = tokio::time::Instant::now() + period;
+                }
+                changed = refresh_interval.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
                 }
-
-                sleep(Duration::from_millis(5000)).await;
-            }
-            Err(e) => {
-                debug!("Failed to connect to response server: {}", e);
-                sleep(Duration::from_millis(2000)).await;
             }
         }
     }
 }
 
-async fn periodic_refresh(file_index: Arc<Mutex<FileIndex>>) {
-    let mut interval = tokio::time::interval(Duration::from_secs(300));
+/// Installs the global subscriber. As with `tracing_subscriber::fmt::init`,
+/// `RUST_LOG` may name a maximum level.
+fn init_logging(format: LogFormat) {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
 
-    loop {
-        interval.tick().await;
-        info!("Performing periodic file index refresh...");
+/// Builds an index for `settings` with the options every namespace shares.
+/// `namespace` is `None` for the default index, the only one that records
+/// selection history and reads `--file-list`.
+fn build_index(args: &Args, settings: &Settings, namespace: Option<&str>) -> Result<FileIndex> {
+    let index = FileIndex::with_roots(settings.roots.clone())
+        .with_metadata(args.metadata)
+        .with_dirs(args.include_dirs)
+        .with_hidden(args.include_hidden)
+        .with_follow_symlinks(args.follow_symlinks)
+        .with_match_mode(settings.match_mode)
+        .with_match_weights(settings.match_weights())
+        .with_depth_penalty(settings.depth_penalty)
+        .with_extension_boosts(&settings.extension_boosts)
+        .with_boundary_bonus(settings.boundary_bonus)
+        .with_max_limit(args.max_limit)
+        .with_max_files(args.max_files)
+        .with_prescreen(!args.no_prescreen)
+        .with_name_index(args.sorted_index)
+        .with_path_aliases(PathAliases::new(settings.path_aliases.clone()))
+        .with_exclude(&settings.exclude)?
+        .with_include(&settings.include)?;
+    let index = if args.no_history || namespace.is_some() {
+        index
+    } else {
+        index.with_frecency(FrecencyTable::load(FrecencyTable::default_path()))
+    };
+    // The file list describes the default roots only.
+    let index = match (&args.file_list, namespace) {
+        (Some(list), None) => index.with_scanner(Arc::new(FileListScanner::new(list.clone()))),
+        _ => index,
+    };
+    info!(
+        "Indexing roots for namespace {}: {:?}",
+        namespace.unwrap_or(DEFAULT_NAMESPACE),
+        index.roots()
+    );
+    if args.no_cache {
+        return Ok(index);
+    }
+    let cache_file = match namespace {
+        Some(name) => cache::default_path().with_file_name(format!("index-{}.bin", name)),
+        None => cache::default_path(),
+    };
+    Ok(index.with_cache(cache_file))
+}
 
-        let mut index = file_index.lock().unwrap();
-        if let Err(e) = index.update() {
-            error!("Periodic refresh failed: {}", e);
-        }
+/// Fills `index` from its cache or a first scan, then starts its periodic
+/// refresh and file watcher.
+async fn start_index(
+    args: &Args,
+    mut index: FileIndex,
+    refresh_interval: watch::Receiver<Duration>,
+    metrics: &Arc<Metrics>,
+    watching: bool,
+) -> Result<Arc<RwLock<FileIndex>>> {
+    let warm_start = index.load_cache();
+    if !warm_start
+        && !args.lazy_init
+        && let Err(e) = index.update()
+    {
+        error!("Failed to initialize file index: {}", e);
+        return Err(e);
+    }
+    let file_index = Arc::new(RwLock::new(index));
+
+    if warm_start || args.lazy_init {
+        // Serve the cached (or still empty) list right away and catch up in
+        // the background.
+        let file_index = Arc::clone(&file_index);
+        let timeout = args.refresh_timeout();
+        tokio::spawn(async move {
+            if let Err(e) = rescan(file_index, timeout).await {
+                error!("Initial refresh failed: {}", e);
+            }
+        });
+    }
+
+    tokio::spawn(periodic_refresh(
+        Arc::clone(&file_index),
+        refresh_interval,
+        Arc::clone(metrics),
+        args.refresh_timeout(),
+    ));
+
+    if watching {
+        tokio::spawn(watcher::watch_roots(Arc::clone(&file_index)));
     }
+    Ok(file_index)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let started = Instant::now();
+    let args = Args::parse();
+    init_logging(args.log_format);
 
     info!("Starting quickfile daemon...");
 
-    let file_index = Arc::new(Mutex::new(FileIndex::new()));
+    let mut config = config::Config::load(&config::Config::default_path());
+    let namespace_configs = std::mem::take(&mut config.namespaces);
+    let settings = resolve_settings(&args, config);
 
-    {
-        let mut index = file_index.lock().unwrap();
-        if let Err(e) = index.update() {
-            error!("Failed to initialize file index: {}", e);
-            return Err(e);
+    let metrics = Arc::new(Metrics::default());
+    let (refresh_interval, refresh_interval_rx) =
+        watch::channel(Duration::from_secs(settings.refresh_secs));
+    let index = build_index(&args, &settings, None)?;
+    let file_index =
+        start_index(&args, index, refresh_interval_rx, &metrics, args.watching()).await?;
+
+    if let Some(command) = settings.stream_command.clone() {
+        tokio::spawn(stream_command::run(Arc::clone(&file_index), command));
+    }
+
+    let mut namespaces = BTreeMap::new();
+    for (name, namespace) in namespace_configs {
+        let valid_name = name != DEFAULT_NAMESPACE
+            && !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            anyhow::bail!("Invalid namespace name {:?}", name);
+        }
+        if namespace.roots.is_empty() {
+            anyhow::bail!("Namespace {} has no roots", name);
         }
+        let config = namespace.clone();
+        let settings = Settings {
+            roots: namespace
+                .roots
+                .into_iter()
+                .map(|root| std::path::absolute(&root).unwrap_or(root))
+                .collect(),
+            exclude: namespace
+                .exclude
+                .unwrap_or_else(|| settings.exclude.clone()),
+            include: namespace
+                .include
+                .unwrap_or_else(|| settings.include.clone()),
+            refresh_secs: namespace.refresh_secs.unwrap_or(settings.refresh_secs),
+            ..settings.clone()
+        };
+        let (refresh_interval, refresh_interval_rx) =
+            watch::channel(Duration::from_secs(settings.refresh_secs));
+        let index = build_index(&args, &settings, Some(&name))?;
+        let file_index =
+            start_index(&args, index, refresh_interval_rx, &metrics, !args.no_watch).await?;
+        namespaces.insert(
+            name,
+            Namespace {
+                file_index,
+                config,
+                _refresh_interval: refresh_interval,
+            },
+        );
     }
 
-    let response_writer = Arc::new(Mutex::new(None));
+    let indexes = std::iter::once(&file_index)
+        .chain(namespaces.values().map(|namespace| &namespace.file_index))
+        .map(Arc::clone)
+        .collect();
+    tokio::spawn(refresh_on_signal(
+        indexes,
+        Arc::clone(&metrics),
+        args.refresh_timeout(),
+    ));
 
-    let active_clients = Arc::new(AtomicUsize::new(0));
+    let request_socket = settings.request_socket.clone();
+    let response_socket = settings.response_socket.clone();
+
+    // Clients may bind the default response socket, so make sure its
+    // directory exists.
+    prepare_socket_dir(&response_socket)?;
+    remove_stale_socket(&response_socket);
+
+    let (shutdown, _) = watch::channel(false);
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        signal_shutdown.send_replace(true);
+    });
+
+    let ctx = ServerContext {
+        file_index,
+        namespaces: Arc::new(namespaces),
+        default_response_socket: response_socket.clone(),
+        connections: Arc::default(),
+        client_slots: Arc::new(Semaphore::new(args.max_clients as usize)),
+        refresh_interval,
+        started,
+        metrics,
+        shutdown,
+        allow_shutdown_request: !args.no_shutdown_request,
+        args: Arc::new(args),
+        settings: Arc::new(Mutex::new(settings)),
+    };
+    let listen_tcp = ctx.args.listen_tcp;
+    start_socket_server(request_socket, listen_tcp, ctx).await?;
+
+    remove_stale_socket(&response_socket);
+    info!("Shutdown complete");
 
-    let refresh_index = Arc::clone(&file_index);
-    tokio::spawn(periodic_refresh(refresh_index));
+    Ok(())
+}
 
-    let response_manager_writer = Arc::clone(&response_writer);
-    let response_manager_clients = Arc::clone(&active_clients);
-    tokio::spawn(manage_response_connection(
-        response_manager_writer,
-        response_manager_clients,
-    ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qs_daemon::{EntryType, FileEntry, FileMetadata, ScanProblems, file_id};
+    use tokio::io::AsyncBufReadExt;
+
+    fn context(paths: &[&str]) -> ServerContext {
+        let args = Args::try_parse_from(["qs-daemon", "--no-watch"]).unwrap();
+        let settings = resolve_settings(&args, config::Config::default());
+        let files = paths
+            .iter()
+            .map(|path| FileEntry {
+                id: file_id(path),
+                path: path.to_string(),
+                display_path: path.to_string(),
+                entry_type: EntryType::File,
+                metadata: FileMetadata::default(),
+            })
+            .collect();
+        let mut file_index = FileIndex::new();
+        file_index.swap_files(PreparedFiles::new(files, ScanProblems::default(), false));
+        ServerContext {
+            file_index: Arc::new(RwLock::new(file_index)),
+            namespaces: Arc::default(),
+            default_response_socket: settings.response_socket.clone(),
+            connections: Arc::default(),
+            client_slots: Arc::new(Semaphore::new(args.max_clients as usize)),
+            refresh_interval: watch::channel(Duration::from_secs(settings.refresh_secs)).0,
+            started: Instant::now(),
+            metrics: Arc::default(),
+            shutdown: watch::channel(false).0,
+            allow_shutdown_request: true,
+            args: Arc::new(args),
+            settings: Arc::new(Mutex::new(settings)),
+        }
+    }
 
-    start_socket_server(file_index, response_writer, active_clients).await?;
+    /// Sends one line-framed request from `peer` and returns the reply.
+    async fn exchange(ctx: ServerContext, peer: Peer, request: &str) -> serde_json::Value {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(handle_client(server, peer, ctx));
+        let (reader, mut writer) = tokio::io::split(client);
+        writer.write_all(request.as_bytes()).await.unwrap();
+        writer.write_all(b"\n").await.unwrap();
+        let mut reply = String::new();
+        BufReader::new(reader).read_line(&mut reply).await.unwrap();
+        drop(writer);
+        served.await.unwrap().unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
 
-    Ok(())
+    #[tokio::test]
+    async fn status_over_duplex() {
+        let ctx = context(&["/src/main.rs", "/src/lib.rs"]);
+        let reply = exchange(ctx, Peer::Unix, r#"{"type":"Status"}"#).await;
+        assert_eq!(reply["type"], "Status");
+        assert_eq!(reply["files_count"], 2);
+        assert_eq!(reply["active_clients"], 1);
+        assert_eq!(reply["index_capped"], false);
+    }
+
+    #[tokio::test]
+    async fn subscribe_rejects_socket_outside_socket_dir() {
+        let outside = tempfile::tempdir().unwrap();
+        let path = outside.path().join("response.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        let request = serde_json::json!({ "type": "Subscribe", "response_socket": path });
+        let reply = exchange(context(&[]), Peer::Unix, &request.to_string()).await;
+        assert_eq!(reply["type"], "Error");
+        assert!(
+            reply["message"]
+                .as_str()
+                .unwrap()
+                .contains("outside the daemon's socket directory")
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribe_accepts_socket_in_socket_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = context(&[]);
+        ctx.default_response_socket = dir.path().join("response.sock");
+        let path = dir.path().join("response-1.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let request = serde_json::json!({ "type": "Subscribe", "response_socket": path });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(handle_client(server, Peer::Unix, ctx));
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(format!("{}\n", request).as_bytes())
+            .await
+            .unwrap();
+
+        let (subscribed, _) = listener.accept().await.unwrap();
+        let mut reply = String::new();
+        BufReader::new(subscribed)
+            .read_line(&mut reply)
+            .await
+            .unwrap();
+        assert_eq!(reply.trim_end(), r#"{"type":"Subscribed"}"#);
+        drop((reader, writer));
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribe_is_refused_over_tcp() {
+        let peer = Peer::Tcp("127.0.0.1:40000".parse().unwrap());
+        let reply = exchange(context(&[]), peer, r#"{"type":"Subscribe"}"#).await;
+        assert_eq!(reply["type"], "Error");
+        assert_eq!(reply["message"], "Subscribe is not available over TCP");
+    }
 }
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters reported by `Metrics` requests, updated by every connection
+/// and by the periodic refresh task.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    searches: AtomicU64,
+    search_micros: AtomicU64,
+    refreshes: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a search that ran to completion and the time it took.
+    pub fn record_search(&self, took: Duration) {
+        self.searches.fetch_add(1, Ordering::Relaxed);
+        self.search_micros
+            .fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a successful full or partial refresh.
+    pub fn record_refresh(&self) {
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn searches(&self) -> u64 {
+        self.searches.load(Ordering::Relaxed)
+    }
+
+    pub fn refreshes(&self) -> u64 {
+        self.refreshes.load(Ordering::Relaxed)
+    }
+
+    /// Mean search latency in milliseconds, 0 before the first search.
+    pub fn avg_search_ms(&self) -> f64 {
+        let searches = self.searches();
+        if searches == 0 {
+            return 0.0;
+        }
+        self.search_micros.load(Ordering::Relaxed) as f64 / searches as f64 / 1000.0
+    }
+}
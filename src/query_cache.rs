@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{SearchRequest, SearchResponse};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Number of recent searches kept.
+const CAPACITY: usize = 32;
+
+/// Results of the most recent searches, so a query that is re-issued (for
+/// example after a backspace in the picker) is answered without scoring.
+/// Entries are only served while the index is unchanged since they were
+/// computed.
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    /// Least recently used first.
+    entries: VecDeque<CachedSearch>,
+}
+
+#[derive(Debug)]
+struct CachedSearch {
+    request: SearchRequest,
+    index_updated: SystemTime,
+    response: SearchResponse,
+}
+
+impl QueryCache {
+    /// Returns the cached response for `request` if the index has not been
+    /// updated since it was computed.
+    pub fn get(
+        &mut self,
+        request: &SearchRequest,
+        index_updated: SystemTime,
+    ) -> Option<SearchResponse> {
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| entry.request == *request && entry.index_updated == index_updated)?;
+        let entry = self.entries.remove(position)?;
+        let response = entry.response.clone();
+        self.entries.push_back(entry);
+        Some(response)
+    }
+
+    pub fn insert(
+        &mut self,
+        request: SearchRequest,
+        index_updated: SystemTime,
+        response: SearchResponse,
+    ) {
+        self.entries.retain(|entry| entry.request != request);
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(CachedSearch {
+            request,
+            index_updated,
+            response,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(query: &str) -> SearchRequest {
+        serde_json::from_value(serde_json::json!({ "query": query })).unwrap()
+    }
+
+    /// A response told apart by its `total_files`.
+    fn response(total_files: usize) -> SearchResponse {
+        SearchResponse {
+            results: Vec::new(),
+            results_count: 0,
+            total_files,
+            has_more: false,
+            limit_capped: false,
+            took_ms: 0,
+            scored_files: 0,
+            skipped_files: 0,
+            timed_out: false,
+        }
+    }
+
+    fn cached(cache: &mut QueryCache, query: &str, updated: SystemTime) -> Option<usize> {
+        cache
+            .get(&request(query), updated)
+            .map(|response| response.total_files)
+    }
+
+    #[test]
+    fn entries_expire_when_the_index_is_updated() {
+        let before = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let after = before + Duration::from_secs(1);
+        let mut cache = QueryCache::default();
+        cache.insert(request("main"), before, response(1));
+
+        assert_eq!(cached(&mut cache, "main", before), Some(1));
+        assert_eq!(cached(&mut cache, "main", after), None);
+        assert_eq!(cached(&mut cache, "mai", before), None);
+
+        // A fresh answer replaces the stale one rather than sitting next to it.
+        cache.insert(request("main"), after, response(2));
+        assert_eq!(cached(&mut cache, "main", after), Some(2));
+        assert_eq!(cached(&mut cache, "main", before), None);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry_when_full() {
+        let updated = SystemTime::UNIX_EPOCH;
+        let mut cache = QueryCache::default();
+        for i in 0..CAPACITY {
+            cache.insert(request(&format!("q{i}")), updated, response(i));
+        }
+        assert_eq!(cache.entries.len(), CAPACITY);
+        // Reading q0 makes q1 the least recently used.
+        assert_eq!(cached(&mut cache, "q0", updated), Some(0));
+
+        cache.insert(request("new"), updated, response(CAPACITY));
+        assert_eq!(cache.entries.len(), CAPACITY);
+        assert_eq!(cached(&mut cache, "q1", updated), None);
+        assert_eq!(cached(&mut cache, "q0", updated), Some(0));
+        assert_eq!(cached(&mut cache, "q2", updated), Some(2));
+        assert_eq!(cached(&mut cache, "new", updated), Some(CAPACITY));
+    }
+}
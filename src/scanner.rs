@@ -0,0 +1,1075 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{EntryType, FileEntry, FileMetadata, PathAliases, RootError, ScanProblems};
+use anyhow::Result;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Compiled exclude globs. A pattern containing `/` is matched against the
+/// absolute path; a bare pattern such as `node_modules` or `*.log` matches
+/// any single path component below the root, like fd's `--exclude`.
+#[derive(Debug, Clone)]
+pub struct ExcludeMatcher {
+    patterns: Vec<String>,
+    name_globs: GlobSet,
+    path_globs: GlobSet,
+}
+
+impl Default for ExcludeMatcher {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            name_globs: GlobSet::empty(),
+            path_globs: GlobSet::empty(),
+        }
+    }
+}
+
+impl ExcludeMatcher {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let (name_globs, path_globs) = build_globs(patterns)?;
+        Ok(Self {
+            patterns: patterns.to_vec(),
+            name_globs,
+            path_globs,
+        })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Patterns fd understands with the same meaning.
+    fn name_patterns(&self) -> impl Iterator<Item = &str> {
+        self.patterns
+            .iter()
+            .filter(|pattern| !pattern.contains('/'))
+            .map(String::as_str)
+    }
+
+    pub fn is_excluded(&self, path: &Path, root: &Path) -> bool {
+        if self.path_globs.is_match(path) {
+            return true;
+        }
+        path.strip_prefix(root).is_ok_and(|relative| {
+            relative
+                .components()
+                .any(|component| self.name_globs.is_match(component.as_os_str()))
+        })
+    }
+}
+
+/// Compiled include globs. When there are any, only files matching one of
+/// them are indexed: a pattern containing `/` is matched against the
+/// absolute path, a bare pattern such as `*.rs` against the file name.
+/// Directories are never filtered, so every file below them is still
+/// visited.
+#[derive(Debug, Clone)]
+pub struct IncludeMatcher {
+    patterns: Vec<String>,
+    name_globs: GlobSet,
+    path_globs: GlobSet,
+}
+
+impl Default for IncludeMatcher {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            name_globs: GlobSet::empty(),
+            path_globs: GlobSet::empty(),
+        }
+    }
+}
+
+impl IncludeMatcher {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let (name_globs, path_globs) = build_globs(patterns)?;
+        Ok(Self {
+            patterns: patterns.to_vec(),
+            name_globs,
+            path_globs,
+        })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// The extensions to hand fd as `--extension` when every pattern is a
+    /// plain `*.ext`, which fd then filters on itself.
+    fn extensions(&self) -> Option<Vec<&str>> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .map(|pattern| {
+                pattern
+                    .strip_prefix("*.")
+                    .filter(|ext| !ext.is_empty() && ext.chars().all(char::is_alphanumeric))
+            })
+            .collect()
+    }
+
+    pub fn is_included(&self, path: &Path) -> bool {
+        self.patterns.is_empty()
+            || self.path_globs.is_match(path)
+            || path
+                .file_name()
+                .is_some_and(|name| self.name_globs.is_match(name))
+    }
+}
+
+/// Splits `patterns` into those matched against a single path component and
+/// those, containing `/`, matched against the whole path.
+fn build_globs(patterns: &[String]) -> Result<(GlobSet, GlobSet)> {
+    let mut name_globs = GlobSetBuilder::new();
+    let mut path_globs = GlobSetBuilder::new();
+    for pattern in patterns {
+        if pattern.contains('/') {
+            path_globs.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+        } else {
+            name_globs.add(Glob::new(pattern)?);
+        }
+    }
+    Ok((name_globs.build()?, path_globs.build()?))
+}
+
+/// Keeps one entry per real file, since overlapping roots (or a root reached
+/// through a symlink or a hard link) report the same file under several
+/// paths. The entry with the shortest display path wins; ties keep the
+/// first one scanned.
+fn dedup_canonical(files: Vec<FileEntry>) -> Vec<FileEntry> {
+    let mut seen: HashMap<FileKey, usize> = HashMap::with_capacity(files.len());
+    let mut unique: Vec<FileEntry> = Vec::with_capacity(files.len());
+    for file in files {
+        match seen.entry(FileKey::of(&file.path)) {
+            Entry::Occupied(slot) => {
+                let kept = &mut unique[*slot.get()];
+                if file.display_path.len() < kept.display_path.len() {
+                    *kept = file;
+                }
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(unique.len());
+                unique.push(file);
+            }
+        }
+    }
+    unique
+}
+
+/// What makes two paths the same file: the device and inode they lead to,
+/// or the canonical path where there are no inodes or the file is gone.
+#[derive(PartialEq, Eq, Hash)]
+enum FileKey {
+    #[cfg(unix)]
+    Inode(u64, u64),
+    Path(PathBuf),
+}
+
+impl FileKey {
+    fn of(path: &str) -> Self {
+        #[cfg(unix)]
+        if let Ok(meta) = std::fs::metadata(path) {
+            use std::os::unix::fs::MetadataExt;
+            return FileKey::Inode(meta.dev(), meta.ino());
+        }
+        FileKey::Path(std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)))
+    }
+}
+
+/// Canonical forms of the directories seen so far. A file that is not a
+/// symlink canonicalizes to its canonical directory joined with its name,
+/// so [`crate::file_id`] can skip resolving every component of every path.
+#[derive(Default)]
+struct CanonicalDirs {
+    dirs: HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl CanonicalDirs {
+    /// Same as [`crate::file_id`]. `is_symlink` saves a stat() when the
+    /// metadata already says.
+    fn file_id(&mut self, path: &str, is_symlink: Option<bool>) -> u64 {
+        let file = Path::new(path);
+        let is_symlink = is_symlink.or_else(|| {
+            std::fs::symlink_metadata(file)
+                .ok()
+                .map(|meta| meta.file_type().is_symlink())
+        });
+        let (Some(false), Some(dir), Some(name)) = (is_symlink, file.parent(), file.file_name())
+        else {
+            return crate::file_id(path);
+        };
+        if !self.dirs.contains_key(dir) {
+            self.dirs
+                .insert(dir.to_path_buf(), std::fs::canonicalize(dir).ok());
+        }
+        match &self.dirs[dir] {
+            Some(canonical_dir) => canonical_dir
+                .join(name)
+                .to_str()
+                .map_or_else(|| crate::file_id(path), crate::path_hash),
+            None => crate::file_id(path),
+        }
+    }
+}
+
+/// Modification time in unix seconds, from the collected metadata when
+/// available and from the filesystem otherwise.
+fn modified_secs(file: &FileEntry) -> Option<u64> {
+    file.metadata.modified.or_else(|| {
+        std::fs::symlink_metadata(&file.path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|age| age.as_secs())
+    })
+}
+
+/// Settings shared by every scanner implementation.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    pub exclude: ExcludeMatcher,
+    /// Allowlist applied to files after the excludes.
+    pub include: IncludeMatcher,
+    /// Report directories in addition to files.
+    pub include_dirs: bool,
+    /// Descend into and report hidden (dot) entries.
+    pub include_hidden: bool,
+    /// Follow symbolic links to directories and files.
+    pub follow_symlinks: bool,
+    /// Once set, scanners stop early and fail with a "Scan cancelled"
+    /// error.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl ScanOptions {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            anyhow::bail!("Scan cancelled");
+        }
+        Ok(())
+    }
+}
+
+/// One path found by a scanner.
+#[derive(Debug, Clone)]
+pub struct ScannedEntry {
+    pub path: String,
+    pub entry_type: EntryType,
+}
+
+/// Everything a scanner found below one root.
+#[derive(Debug, Default)]
+pub struct RootScan {
+    pub entries: Vec<ScannedEntry>,
+    /// Problems that did not stop the scan, such as directories fd could
+    /// not read. Files below them are missing.
+    pub warning: Option<String>,
+}
+
+impl From<Vec<ScannedEntry>> for RootScan {
+    fn from(entries: Vec<ScannedEntry>) -> Self {
+        Self {
+            entries,
+            warning: None,
+        }
+    }
+}
+
+/// Everything needed to rebuild the file list. It is cheap to clone, so a
+/// refresh can take a copy and scan without holding the index lock.
+#[derive(Clone)]
+pub struct ScanConfig {
+    pub roots: Vec<PathBuf>,
+    pub scanner: Arc<dyn FileScanner>,
+    pub options: ScanOptions,
+    /// Prefixes shortened in `display_path`.
+    pub aliases: PathAliases,
+    pub collect_metadata: bool,
+    /// Upper bound on indexed entries, see [`ScanConfig::limit_files`].
+    pub max_files: Option<usize>,
+}
+
+impl ScanConfig {
+    /// Scans every root, returning the entries of the roots that could be
+    /// scanned along with the errors of those that could not and the
+    /// warnings of those that scanned only partly. Fails only when no root
+    /// could be scanned.
+    pub fn scan(&self) -> Result<(Vec<FileEntry>, ScanProblems)> {
+        let mut files = Vec::new();
+        let mut problems = ScanProblems::default();
+
+        for root in &self.roots {
+            let scanned = match self.scanner.scan(root, &self.options) {
+                Ok(scanned) => scanned,
+                Err(e) => {
+                    // A cancelled scan is not a broken root.
+                    self.options.check_cancelled()?;
+                    warn!("Failed to scan {}: {:#}", root.display(), e);
+                    problems.errors.push(RootError {
+                        root: root.clone(),
+                        message: format!("{:#}", e),
+                    });
+                    continue;
+                }
+            };
+            if let Some(message) = scanned.warning {
+                problems.warnings.push(RootError {
+                    root: root.clone(),
+                    message,
+                });
+            }
+            files.extend(self.make_entries(&scanned.entries));
+        }
+        self.options.check_cancelled()?;
+        let errors = &problems.errors;
+        if !self.roots.is_empty() && errors.len() == self.roots.len() {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|error| format!("{}: {}", error.root.display(), error.message))
+                .collect();
+            anyhow::bail!("No root could be scanned: {}", messages.join("; "));
+        }
+
+        // Only pay for a stat() of every path when roots could overlap.
+        if self.roots.len() > 1 {
+            files = dedup_canonical(files);
+        }
+
+        problems.capped = self.limit_files(&mut files);
+        Ok((files, problems))
+    }
+
+    /// Enforces `max_files` by keeping the most recently modified entries.
+    /// Entries whose modification time cannot be read are dropped first;
+    /// the survivors keep their scan order. Returns whether any entry was
+    /// dropped.
+    pub fn limit_files(&self, files: &mut Vec<FileEntry>) -> bool {
+        let Some(max_files) = self.max_files.filter(|&max| files.len() > max) else {
+            return false;
+        };
+
+        let mut by_age: Vec<(Reverse<u64>, usize)> = files
+            .par_iter()
+            .enumerate()
+            .map(|(index, file)| (Reverse(modified_secs(file).unwrap_or(0)), index))
+            .collect();
+        if max_files == 0 {
+            by_age.clear();
+        } else {
+            by_age.select_nth_unstable(max_files - 1);
+            by_age.truncate(max_files);
+        }
+        let mut keep = vec![false; files.len()];
+        for (_, index) in by_age {
+            keep[index] = true;
+        }
+        let mut keep = keep.into_iter();
+        let total = files.len();
+        files.retain(|_| keep.next().unwrap_or(false));
+        warn!(
+            "Index capped at {} entries, left out the {} least recently modified",
+            max_files,
+            total - files.len()
+        );
+        true
+    }
+
+    pub fn make_entry(&self, path: &str, entry_type: EntryType) -> FileEntry {
+        self.entry(path, entry_type, &mut CanonicalDirs::default())
+    }
+
+    /// Entries for a whole scan, resolving each directory only once for
+    /// the ids.
+    fn make_entries(&self, entries: &[ScannedEntry]) -> Vec<FileEntry> {
+        let mut dirs = CanonicalDirs::default();
+        entries
+            .iter()
+            .map(|entry| self.entry(&entry.path, entry.entry_type, &mut dirs))
+            .collect()
+    }
+
+    fn entry(&self, path: &str, entry_type: EntryType, dirs: &mut CanonicalDirs) -> FileEntry {
+        let metadata = if self.collect_metadata {
+            FileMetadata::read(path)
+        } else {
+            FileMetadata::default()
+        };
+        // fd cannot say whether a path is a symlink, but the stat() done for
+        // metadata can.
+        let entry_type = if metadata.is_symlink == Some(true) {
+            EntryType::Symlink
+        } else {
+            entry_type
+        };
+        FileEntry {
+            id: dirs.file_id(path, metadata.is_symlink),
+            path: path.to_string(),
+            display_path: self.aliases.display_path(path),
+            entry_type,
+            metadata,
+        }
+    }
+
+    /// Rescans only `dir`, which must lie inside one of the roots. A
+    /// directory that no longer exists yields no entries.
+    pub fn scan_subtree(&self, dir: &Path) -> Result<Vec<FileEntry>> {
+        let inside_root = dir.is_absolute()
+            && !dir
+                .components()
+                .any(|component| component == Component::ParentDir)
+            && self.roots.iter().any(|root| dir.starts_with(root));
+        if !inside_root {
+            anyhow::bail!("{} is not inside an indexed root", dir.display());
+        }
+        if self.is_excluded(dir) || !dir.exists() {
+            return Ok(Vec::new());
+        }
+        if !dir.is_dir() {
+            anyhow::bail!("{} is not a directory", dir.display());
+        }
+
+        let entries = self.scanner.scan(dir, &self.options)?.entries;
+        Ok(self.make_entries(&entries))
+    }
+
+    /// Whether `path` has a dot-prefixed component below its root.
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .any(|relative| {
+                relative
+                    .components()
+                    .any(|component| component.as_os_str().as_encoded_bytes().starts_with(b"."))
+            })
+    }
+
+    /// Whether an ignore file below a root `path` is in ignores it, the
+    /// way the scanners would.
+    pub fn is_ignored(&self, rules: &mut IgnoreRules, path: &Path, is_dir: bool) -> bool {
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .any(|root| rules.is_ignored(root, path, is_dir))
+    }
+
+    /// Whether `path` falls under an exclude pattern of any root it is in.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .any(|root| self.options.exclude.is_excluded(path, root))
+    }
+}
+
+/// The `.gitignore`, `.ignore` and `.fdignore` files both scanners honor,
+/// for paths that arrive one at a time, such as watcher events. As with
+/// fd, `.gitignore` files only count inside a git repository. Parsed files
+/// are kept as long as the value lives, so use one per batch of paths.
+#[derive(Default)]
+pub struct IgnoreRules {
+    dirs: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl IgnoreRules {
+    /// Whether the ignore files in `root` and the directories between it
+    /// and `path` ignore `path` or one of its parents. The deepest file
+    /// with a matching rule decides.
+    pub fn is_ignored(&mut self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(root) {
+                break;
+            }
+            let rules = self
+                .dirs
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| load_ignore_files(dir));
+            if let Some(rules) = rules {
+                match rules.matched_path_or_any_parents(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+}
+
+/// The ignore files in `dir`, later ones overriding earlier ones like the
+/// scanners do. `None` when there are none.
+fn load_ignore_files(dir: &Path) -> Option<Gitignore> {
+    let in_repo = dir.ancestors().any(|dir| dir.join(".git").exists());
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in [".gitignore", ".ignore", ".fdignore"] {
+        let file = dir.join(name);
+        if (name == ".gitignore" && !in_repo) || !file.is_file() {
+            continue;
+        }
+        if let Some(e) = builder.add(&file) {
+            debug!("Problem reading {}: {}", file.display(), e);
+        }
+        found = true;
+    }
+    if !found {
+        return None;
+    }
+    builder.build().ok()
+}
+
+/// Produces the list of files below a root.
+pub trait FileScanner: Send + Sync {
+    /// Short name reported in `Status` responses.
+    fn name(&self) -> &'static str;
+
+    fn scan(&self, root: &Path, options: &ScanOptions) -> Result<RootScan>;
+}
+
+/// Shells out to the `fd` binary.
+pub struct FdScanner;
+
+impl FdScanner {
+    /// Whether an `fd` binary can be executed from `PATH`.
+    pub fn is_available() -> bool {
+        Command::new("fd")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+}
+
+impl FileScanner for FdScanner {
+    fn name(&self) -> &'static str {
+        "fd"
+    }
+
+    fn scan(&self, root: &Path, options: &ScanOptions) -> Result<RootScan> {
+        let mut scanned = self.run(root, options, EntryType::File)?;
+        if options.include_dirs {
+            let dirs = self.run(root, options, EntryType::Dir)?;
+            scanned.entries.extend(dirs.entries);
+            // Both runs hit the same unreadable directories.
+            scanned.warning = scanned.warning.or(dirs.warning);
+        }
+        Ok(scanned)
+    }
+}
+
+impl FdScanner {
+    /// Runs fd once for a single entry type, since its output does not say
+    /// which type each path has.
+    fn run(&self, root: &Path, options: &ScanOptions, entry_type: EntryType) -> Result<RootScan> {
+        let fd_type = match entry_type {
+            EntryType::Dir => "directory",
+            EntryType::File | EntryType::Symlink => "file",
+        };
+        let mut command = Command::new("fd");
+        // NUL-separated output keeps file names containing newlines intact.
+        command
+            .arg(".")
+            .arg(root)
+            .args(["--type", fd_type])
+            .arg("--print0");
+        if options.include_hidden {
+            command.arg("--hidden");
+        }
+        if options.follow_symlinks {
+            command.arg("--follow");
+        }
+        for pattern in options.exclude.name_patterns() {
+            command.args(["--exclude", pattern]);
+        }
+        if entry_type != EntryType::Dir
+            && let Some(extensions) = options.include.extensions()
+        {
+            for extension in extensions {
+                command.args(["--extension", extension]);
+            }
+        }
+        let output = run_cancellable(command, options)?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "fd command failed for {}: {}",
+                root.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        // fd still exits successfully after printing warnings, e.g. for
+        // directories it has no permission to read.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        let warning = (!stderr.is_empty()).then(|| {
+            warn!(
+                "fd reported problems scanning {}:\n{}",
+                root.display(),
+                stderr
+            );
+            summarize_warning(stderr)
+        });
+
+        let entries = output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter(|entry| !entry.is_empty())
+            // Like the built-in walker, skip paths that are not UTF-8.
+            .filter_map(|entry| std::str::from_utf8(entry).ok())
+            .map(|path| path.strip_suffix('/').unwrap_or(path))
+            .filter(|path| !options.exclude.is_excluded(Path::new(path), root))
+            .filter(|path| {
+                entry_type == EntryType::Dir || options.include.is_included(Path::new(path))
+            })
+            .map(|path| ScannedEntry {
+                path: path.to_string(),
+                entry_type,
+            })
+            .collect();
+        Ok(RootScan { entries, warning })
+    }
+}
+
+/// The first few lines of a scanner's warnings, so one unreadable tree
+/// cannot bloat every refresh reply.
+fn summarize_warning(warning: &str) -> String {
+    const MAX_LINES: usize = 5;
+    const MAX_LINE_CHARS: usize = 200;
+
+    let lines: Vec<&str> = warning.lines().collect();
+    let mut summary: Vec<String> = lines
+        .iter()
+        .take(MAX_LINES)
+        .map(|line| match line.char_indices().nth(MAX_LINE_CHARS) {
+            Some((end, _)) => format!("{}…", &line[..end]),
+            None => line.to_string(),
+        })
+        .collect();
+    if lines.len() > MAX_LINES {
+        summary.push(format!("({} more lines)", lines.len() - MAX_LINES));
+    }
+    summary.join("\n")
+}
+
+/// Runs `command` to completion like [`Command::output`], but kills it
+/// as soon as the scan is cancelled.
+fn run_cancellable(mut command: Command, options: &ScanOptions) -> Result<Output> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    std::thread::scope(|scope| {
+        // Drain both pipes while waiting so a chatty child cannot block on
+        // a full pipe.
+        let stdout = scope.spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        let stderr = scope.spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        });
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if options.is_cancelled() {
+                // Killing the child closes its pipes, which ends the readers.
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("Scan cancelled");
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("stdout reader panicked")?,
+            stderr: stderr.join().expect("stderr reader panicked")?,
+        })
+    })
+}
+
+/// Pure-Rust walker used when `fd` is not installed. Applies the same
+/// defaults as fd: hidden entries are skipped unless asked for and
+/// `.gitignore`, `.ignore` and `.fdignore` files are honored.
+pub struct WalkdirScanner;
+
+impl FileScanner for WalkdirScanner {
+    fn name(&self) -> &'static str {
+        "walkdir"
+    }
+
+    fn scan(&self, root: &Path, options: &ScanOptions) -> Result<RootScan> {
+        if !root.is_dir() {
+            anyhow::bail!("{} is not a directory", root.display());
+        }
+
+        let mut files = Vec::new();
+        let exclude = options.exclude.clone();
+        let walk_root = root.to_path_buf();
+        // With links followed, two symlinks can lead into the same directory,
+        // or one can point back at its own ancestor. Descending into each
+        // directory (by device and inode) only once stops both. Elsewhere
+        // the walker's own loop check has to do.
+        #[cfg(unix)]
+        let visited: Mutex<HashSet<(u64, u64)>> = Mutex::default();
+        let follow_symlinks = options.follow_symlinks;
+        let walker = ignore::WalkBuilder::new(root)
+            .add_custom_ignore_filename(".fdignore")
+            .hidden(!options.include_hidden)
+            .follow_links(follow_symlinks)
+            .filter_entry(move |entry| {
+                if exclude.is_excluded(entry.path(), &walk_root) {
+                    return false;
+                }
+                #[cfg(unix)]
+                if follow_symlinks && entry.file_type().is_some_and(|kind| kind.is_dir()) {
+                    use std::os::unix::fs::MetadataExt;
+                    if let Ok(meta) = std::fs::metadata(entry.path()) {
+                        return visited
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .insert((meta.dev(), meta.ino()));
+                    }
+                }
+                true
+            })
+            .build();
+
+        for entry in walker {
+            options.check_cancelled()?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping unreadable entry: {}", e);
+                    continue;
+                }
+            };
+            let entry_type = match entry.file_type() {
+                Some(kind) if kind.is_file() && options.include.is_included(entry.path()) => {
+                    EntryType::File
+                }
+                Some(kind) if kind.is_dir() && options.include_dirs && entry.depth() > 0 => {
+                    EntryType::Dir
+                }
+                _ => continue,
+            };
+            if let Some(path) = entry.path().to_str() {
+                files.push(ScannedEntry {
+                    path: path.to_string(),
+                    entry_type,
+                });
+            }
+        }
+
+        Ok(files.into())
+    }
+}
+
+/// Reads the paths to index from a file instead of walking the tree, e.g.
+/// the output of `git ls-files` or a build system. Paths are separated by
+/// NUL bytes when the file contains any and by newlines otherwise; relative
+/// paths are taken relative to the list's directory. Each root keeps the
+/// listed files below it that pass the excludes and includes. The list is
+/// trusted as is: hidden files are kept and nothing is checked on disk.
+pub struct FileListScanner {
+    list: PathBuf,
+}
+
+impl FileListScanner {
+    pub fn new(list: PathBuf) -> Self {
+        // Relative entries resolve against the list's directory, so pin it
+        // down while the working directory is the one the user meant.
+        let list = std::path::absolute(&list).unwrap_or(list);
+        Self { list }
+    }
+}
+
+/// Resolves `.` and `..` in an absolute path without touching the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+impl FileScanner for FileListScanner {
+    fn name(&self) -> &'static str {
+        "file-list"
+    }
+
+    fn scan(&self, root: &Path, options: &ScanOptions) -> Result<RootScan> {
+        let contents = std::fs::read(&self.list)
+            .map_err(|e| anyhow::anyhow!("Cannot read file list {}: {}", self.list.display(), e))?;
+        let separator = if contents.contains(&0) { 0 } else { b'\n' };
+        let base = self.list.parent().unwrap_or(Path::new("/"));
+
+        Ok(contents
+            .split(|&byte| byte == separator)
+            .filter_map(|entry| std::str::from_utf8(entry).ok())
+            .map(|path| path.trim_end_matches(['\r', '/']))
+            .filter(|path| !path.is_empty())
+            .map(|path| normalize(&base.join(path)))
+            .filter(|path| path.starts_with(root))
+            .filter(|path| {
+                !options.exclude.is_excluded(path, root) && options.include.is_included(path)
+            })
+            .filter_map(|path| {
+                Some(ScannedEntry {
+                    path: path.to_str()?.to_string(),
+                    entry_type: EntryType::File,
+                })
+            })
+            .collect::<Vec<_>>()
+            .into())
+    }
+}
+
+/// Picks `fd` when it is on `PATH`, otherwise the built-in walker.
+pub fn detect() -> Arc<dyn FileScanner> {
+    if FdScanner::is_available() {
+        info!("Using fd for file discovery");
+        Arc::new(FdScanner)
+    } else {
+        info!("fd not found on PATH, using built-in directory walker");
+        Arc::new(WalkdirScanner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_dirs_agree_with_file_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::fs::write(real.join("a.rs"), "").unwrap();
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(real.join("a.rs"), real.join("b.rs")).unwrap();
+
+        let mut dirs = CanonicalDirs::default();
+        let id = crate::file_id(real.join("a.rs").to_str().unwrap());
+        for path in ["real/a.rs", "link/a.rs", "link/b.rs", "real/b.rs"] {
+            let path = dir.path().join(path);
+            assert_eq!(dirs.file_id(path.to_str().unwrap(), None), id, "{:?}", path);
+        }
+        let gone = dir.path().join("real/gone.rs");
+        let gone = gone.to_str().unwrap();
+        assert_eq!(dirs.file_id(gone, None), crate::file_id(gone));
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn exclude_name_globs_match_any_component_below_the_root() {
+        let exclude = ExcludeMatcher::new(&patterns(&["node_modules", "*.log"])).unwrap();
+        let root = Path::new("/home/user/proj");
+        let excluded = |path: &str| exclude.is_excluded(Path::new(path), root);
+        assert!(excluded("/home/user/proj/node_modules"));
+        assert!(excluded("/home/user/proj/web/node_modules/react/index.js"));
+        assert!(excluded("/home/user/proj/logs/debug.log"));
+        assert!(!excluded("/home/user/proj/src/main.rs"));
+        assert!(!excluded("/home/user/proj/node_modules_backup/a.js"));
+
+        // Components of the root itself are not checked.
+        let root = Path::new("/srv/node_modules/app");
+        assert!(!exclude.is_excluded(Path::new("/srv/node_modules/app/main.js"), root));
+    }
+
+    #[test]
+    fn exclude_path_globs_match_the_absolute_path() {
+        let exclude =
+            ExcludeMatcher::new(&patterns(&["/home/user/proj/build/*", "**/target/**"])).unwrap();
+        let root = Path::new("/home/user/proj");
+        let excluded = |path: &str| exclude.is_excluded(Path::new(path), root);
+        assert!(excluded("/home/user/proj/build/out.o"));
+        // `*` stops at `/`, so only direct children of build/ match.
+        assert!(!excluded("/home/user/proj/build/sub/out.o"));
+        assert!(excluded("/home/user/proj/crate/target/debug/app"));
+        assert!(!excluded("/home/user/proj/src/target.rs"));
+        // Path globs apply outside the root too.
+        assert!(excluded("/elsewhere/target/x"));
+    }
+
+    #[test]
+    fn include_name_globs_match_the_file_name() {
+        let include = IncludeMatcher::new(&patterns(&["*.rs", "Makefile"])).unwrap();
+        let included = |path: &str| include.is_included(Path::new(path));
+        assert!(included("/proj/src/main.rs"));
+        assert!(included("/proj/Makefile"));
+        assert!(!included("/proj/README.md"));
+        // Only the last component counts, unlike excludes.
+        assert!(!included("/proj/lib.rs/notes.txt"));
+
+        assert!(IncludeMatcher::default().is_included(Path::new("/proj/README.md")));
+    }
+
+    #[test]
+    fn include_path_globs_match_the_absolute_path() {
+        let include = IncludeMatcher::new(&patterns(&["/proj/docs/*.md"])).unwrap();
+        let included = |path: &str| include.is_included(Path::new(path));
+        assert!(included("/proj/docs/guide.md"));
+        assert!(!included("/proj/docs/api/index.md"));
+        assert!(!included("/proj/README.md"));
+    }
+
+    #[test]
+    fn excludes_win_over_includes_and_dirs_are_not_included_away() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [
+            "src/main.rs",
+            "src/main_test.rs",
+            "target/gen.rs",
+            "README.md",
+        ] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let options = ScanOptions {
+            exclude: ExcludeMatcher::new(&patterns(&["target", "*_test.rs"])).unwrap(),
+            include: IncludeMatcher::new(&patterns(&["*.rs"])).unwrap(),
+            include_dirs: true,
+            ..ScanOptions::default()
+        };
+
+        let scan = WalkdirScanner.scan(dir.path(), &options).unwrap();
+        let mut found: Vec<(String, EntryType)> = scan
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let path = Path::new(&entry.path).strip_prefix(dir.path()).unwrap();
+                (path.to_str().unwrap().to_string(), entry.entry_type)
+            })
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            found,
+            [
+                ("src".to_string(), EntryType::Dir),
+                ("src/main.rs".to_string(), EntryType::File),
+            ]
+        );
+    }
+
+    fn scanned(path: &Path, display_path: &str) -> FileEntry {
+        FileEntry {
+            id: crate::file_id(path.to_str().unwrap()),
+            path: path.to_str().unwrap().to_string(),
+            display_path: display_path.to_string(),
+            entry_type: EntryType::File,
+            metadata: FileMetadata::default(),
+        }
+    }
+
+    fn display_paths(files: &[FileEntry]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|file| file.display_path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn dedup_canonical_collapses_linked_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::fs::write(real.join("a.rs"), "a").unwrap();
+        std::fs::write(real.join("b.rs"), "b").unwrap();
+        std::fs::hard_link(real.join("a.rs"), dir.path().join("hard.rs")).unwrap();
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(real.join("b.rs"), dir.path().join("bl.rs")).unwrap();
+
+        let files = vec![
+            scanned(&dir.path().join("link/a.rs"), "~/link/a.rs"),
+            scanned(&real.join("a.rs"), "~/real/a.rs"),
+            scanned(&dir.path().join("hard.rs"), "~/hard.rs"),
+            scanned(&real.join("b.rs"), "~/real/b.rs"),
+            scanned(&dir.path().join("bl.rs"), "~/bl.rs"),
+            scanned(&dir.path().join("link/b.rs"), "~/link/b.rs"),
+            scanned(&dir.path().join("gone.rs"), "~/gone.rs"),
+        ];
+        let unique = dedup_canonical(files);
+        assert_eq!(
+            display_paths(&unique),
+            ["~/hard.rs", "~/bl.rs", "~/gone.rs"]
+        );
+    }
+
+    #[test]
+    fn limit_files_keeps_the_newest_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let epoch = std::time::SystemTime::UNIX_EPOCH;
+        let mut files = Vec::new();
+        for (name, age) in [("old", 100), ("newest", 400), ("new", 300), ("older", 50)] {
+            let path = dir.path().join(name);
+            std::fs::File::create(&path)
+                .unwrap()
+                .set_modified(epoch + Duration::from_secs(age))
+                .unwrap();
+            files.push(scanned(&path, name));
+        }
+        // No mtime to read, so it goes first.
+        files.push(scanned(&dir.path().join("gone"), "gone"));
+
+        let mut config = ScanConfig {
+            roots: vec![dir.path().to_path_buf()],
+            scanner: Arc::new(WalkdirScanner),
+            options: ScanOptions::default(),
+            aliases: PathAliases::default(),
+            collect_metadata: false,
+            max_files: Some(5),
+        };
+        assert!(!config.limit_files(&mut files));
+        assert_eq!(files.len(), 5);
+
+        config.max_files = Some(3);
+        assert!(config.limit_files(&mut files));
+        // Survivors keep their scan order.
+        assert_eq!(display_paths(&files), ["old", "newest", "new"]);
+
+        config.max_files = Some(1);
+        assert!(config.limit_files(&mut files));
+        assert_eq!(display_paths(&files), ["newest"]);
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{
+    FileEntry, MatchMode, SearchMatch, has_extension, match_positions, match_target, target_offset,
+};
+use nucleo_matcher::pattern::{CaseMatching, Normalization, Pattern};
+use nucleo_matcher::{Matcher, Utf32Str};
+
+/// A fuzzy query with its `dir:`, `file:` and `ext:` terms split off, e.g.
+/// `dir:src main` looks for `src` in the directory and `main` in the
+/// match mode's usual target. Words without one of these prefixes, or with
+/// nothing after it, stay in `rest` untouched.
+#[derive(Debug, Default)]
+pub struct ScopedQuery {
+    pub rest: String,
+    dir: Option<Pattern>,
+    file: Option<Pattern>,
+    extensions: Vec<String>,
+}
+
+impl ScopedQuery {
+    pub fn parse(query: &str, case: CaseMatching, normalization: Normalization) -> Self {
+        let mut rest = Vec::new();
+        let mut dir = Vec::new();
+        let mut file = Vec::new();
+        let mut extensions = Vec::new();
+        for word in query.split_whitespace() {
+            let scoped = |prefix| word.strip_prefix(prefix).filter(|term| !term.is_empty());
+            if let Some(term) = scoped("dir:") {
+                dir.push(term);
+            } else if let Some(term) = scoped("file:") {
+                file.push(term);
+            } else if let Some(term) = scoped("ext:") {
+                extensions.push(term.trim_start_matches('.').to_string());
+            } else {
+                rest.push(word);
+            }
+        }
+        if dir.is_empty() && file.is_empty() && extensions.is_empty() {
+            // Leave escapes and spacing exactly as typed.
+            return Self {
+                rest: query.to_string(),
+                ..Self::default()
+            };
+        }
+
+        let pattern = |terms: Vec<&str>| {
+            (!terms.is_empty()).then(|| Pattern::parse(&terms.join(" "), case, normalization))
+        };
+        Self {
+            rest: rest.join(" "),
+            dir: pattern(dir),
+            file: pattern(file),
+            extensions,
+        }
+    }
+
+    /// Whether the query had any scoped terms.
+    pub fn is_scoped(&self) -> bool {
+        self.dir.is_some() || self.file.is_some() || !self.extensions.is_empty()
+    }
+
+    /// Sum of the `dir:` and `file:` scores, or `None` when a scoped term
+    /// does not match.
+    pub fn score(
+        &self,
+        matcher: &mut Matcher,
+        haystack_vec: &mut Vec<char>,
+        file: &FileEntry,
+    ) -> Option<u32> {
+        if !self.extensions.is_empty() && !has_extension(&file.path, &self.extensions) {
+            return None;
+        }
+        let mut total = 0;
+        for (pattern, _, part) in self.parts(file) {
+            total += pattern.score(Utf32Str::new(part, haystack_vec), matcher)?;
+        }
+        Some(total)
+    }
+
+    /// Positions within `display_path` matched by the scoped terms.
+    pub fn highlights(
+        &self,
+        matcher: &mut Matcher,
+        haystack_vec: &mut Vec<char>,
+        file: &FileEntry,
+    ) -> Vec<SearchMatch> {
+        let mut matches = Vec::new();
+        for (pattern, start, part) in self.parts(file) {
+            let mut indices = Vec::new();
+            let haystack = Utf32Str::new(part, haystack_vec);
+            if pattern.indices(haystack, matcher, &mut indices).is_some() {
+                matches.extend(match_positions(&file.display_path, start, part, indices));
+            }
+        }
+        matches
+    }
+
+    /// Each scoped pattern with the part of `display_path` it is scored
+    /// against and the byte offset that part starts at.
+    fn parts<'a>(
+        &'a self,
+        file: &'a FileEntry,
+    ) -> impl Iterator<Item = (&'a Pattern, usize, &'a str)> {
+        let name = match_target(file, MatchMode::Filename);
+        let name_start = target_offset(&file.display_path, name);
+        let dir = &file.display_path[..name_start];
+        let dir_part = self.dir.as_ref().map(|pattern| (pattern, 0, dir));
+        let file_part = self
+            .file
+            .as_ref()
+            .map(|pattern| (pattern, name_start, name));
+        dir_part.into_iter().chain(file_part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EntryType, FileMetadata};
+    use nucleo_matcher::Config;
+
+    fn parse(query: &str) -> ScopedQuery {
+        ScopedQuery::parse(query, CaseMatching::Smart, Normalization::Smart)
+    }
+
+    /// Whether `query`'s scoped terms accept `display_path`.
+    fn accepts(query: &ScopedQuery, display_path: &str) -> bool {
+        let file = FileEntry {
+            id: crate::file_id(display_path),
+            path: display_path.to_string(),
+            display_path: display_path.to_string(),
+            entry_type: EntryType::File,
+            metadata: FileMetadata::default(),
+        };
+        let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+        query.score(&mut matcher, &mut Vec::new(), &file).is_some()
+    }
+
+    #[test]
+    fn dir_terms_are_split_from_the_rest() {
+        let query = parse("dir:src main");
+        assert!(query.is_scoped());
+        assert_eq!(query.rest, "main");
+        assert!(accepts(&query, "~/proj/src/main.rs"));
+        assert!(!accepts(&query, "~/proj/lib/main.rs"));
+        // The file name is not part of the directory.
+        assert!(!accepts(&query, "~/proj/src.rs"));
+    }
+
+    #[test]
+    fn ext_terms_filter_by_extension() {
+        for term in ["ext:.rs", "ext:rs", "ext:RS"] {
+            let query = parse(&format!("{term} main"));
+            assert_eq!(query.rest, "main");
+            assert!(accepts(&query, "~/src/main.rs"), "{term}");
+            assert!(!accepts(&query, "~/src/main.py"), "{term}");
+            assert!(!accepts(&query, "~/src/rs"), "{term}");
+        }
+        let query = parse("ext:rs ext:toml");
+        assert_eq!(query.rest, "");
+        assert!(accepts(&query, "~/Cargo.toml"));
+        assert!(!accepts(&query, "~/Cargo.lock"));
+    }
+
+    #[test]
+    fn unscoped_queries_are_kept_as_typed() {
+        let query = parse("dir:  main\\ rs");
+        assert!(!query.is_scoped());
+        assert_eq!(query.rest, "dir:  main\\ rs");
+    }
+}
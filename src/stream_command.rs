@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use anyhow::{Context, Result};
+use qs_daemon::FileIndex;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant, timeout};
+use tracing::{debug, info, warn};
+
+/// How long to wait for more paths before applying the ones read so far.
+const BATCH_WAIT: Duration = Duration::from_millis(100);
+/// Paths applied under one write lock at most.
+const MAX_BATCH: usize = 4096;
+/// Wait before the first restart, doubled after each quick exit up to
+/// `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A command that ran at least this long is restarted after the initial
+/// backoff again.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// Runs `command` through `sh -c` for as long as the daemon runs, adding
+/// every NUL-terminated path it prints to the index. The command is
+/// restarted with backoff whenever it exits.
+pub async fn run(file_index: Arc<RwLock<FileIndex>>, command: String) {
+    let mut quick_exits = 0;
+    loop {
+        let started = Instant::now();
+        match stream_once(&file_index, &command).await {
+            Ok(status) => warn!("Stream command exited with {}", status),
+            Err(e) => warn!("Stream command failed: {:#}", e),
+        }
+        if started.elapsed() >= HEALTHY_RUN {
+            quick_exits = 0;
+        }
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << quick_exits.min(16))
+            .min(MAX_BACKOFF);
+        quick_exits += 1;
+        info!("Restarting stream command in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+async fn stream_once(file_index: &RwLock<FileIndex>, command: &str) -> Result<ExitStatus> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start stream command")?;
+    info!("Started stream command: {}", command);
+    let stdout = child
+        .stdout
+        .take()
+        .context("Stream command has no stdout")?;
+    let mut reader = BufReader::new(stdout);
+    let mut record = Vec::new();
+    let mut batch = Vec::new();
+    loop {
+        // Block for the first path of a batch, then only briefly for more.
+        // A read cut short by the timeout keeps its bytes in `record`.
+        let read = if batch.is_empty() {
+            Some(reader.read_until(0, &mut record).await?)
+        } else {
+            timeout(BATCH_WAIT, reader.read_until(0, &mut record))
+                .await
+                .ok()
+                .transpose()?
+        };
+        match read {
+            Some(0) => break,
+            Some(_) => {
+                if record.last() == Some(&0) {
+                    record.pop();
+                }
+                match String::from_utf8(std::mem::take(&mut record)) {
+                    Ok(path) if path.starts_with('/') => batch.push(PathBuf::from(path)),
+                    Ok(path) => debug!("Ignoring relative path from stream command: {}", path),
+                    Err(_) => debug!("Ignoring non-UTF-8 path from stream command"),
+                }
+                if batch.len() >= MAX_BATCH {
+                    apply(file_index, &mut batch).await;
+                }
+            }
+            None => apply(file_index, &mut batch).await,
+        }
+    }
+    apply(file_index, &mut batch).await;
+    Ok(child.wait().await?)
+}
+
+async fn apply(file_index: &RwLock<FileIndex>, batch: &mut Vec<PathBuf>) {
+    if batch.is_empty() {
+        return;
+    }
+    let count = batch.len();
+    file_index.write().await.add_streamed(batch.drain(..));
+    debug!("Applied {} paths from the stream command", count);
+}
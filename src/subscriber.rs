@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tracing::{error, info, warn};
+
+/// Wait before the first reconnect after a failure, doubled after each
+/// further one up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Consecutive failed writes and reconnects after which the socket is given
+/// up until the client subscribes again.
+const MAX_FAILURES: u32 = 5;
+
+/// A client's subscribed response socket. The connection stays open
+/// between responses; after a failed write it is reopened on a later
+/// response once the backoff has passed, so no response waits for it.
+#[derive(Debug)]
+pub struct ResponseSubscriber {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl ResponseSubscriber {
+    pub async fn connect(path: PathBuf) -> std::io::Result<Self> {
+        let stream = UnixStream::connect(&path).await?;
+        Ok(Self {
+            path,
+            stream: Some(stream),
+            failures: 0,
+            retry_at: Instant::now(),
+        })
+    }
+
+    /// The open connection, reconnecting first when one is due. `None`
+    /// while the socket is unreachable.
+    pub async fn stream(&mut self) -> Option<&mut UnixStream> {
+        if self.stream.is_none() && !self.gave_up() && Instant::now() >= self.retry_at {
+            match UnixStream::connect(&self.path).await {
+                Ok(stream) => {
+                    info!("Reconnected response socket {}", self.path.display());
+                    self.stream = Some(stream);
+                }
+                Err(e) => self.record_failure(&e),
+            }
+        }
+        self.stream.as_mut()
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Closes the connection and schedules the next reconnect.
+    pub fn record_failure(&mut self, e: &std::io::Error) {
+        self.stream = None;
+        self.failures += 1;
+        if self.gave_up() {
+            error!(
+                "Giving up on response socket {} after {} failures: {}",
+                self.path.display(),
+                self.failures,
+                e
+            );
+            return;
+        }
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << (self.failures - 1))
+            .min(MAX_BACKOFF);
+        warn!(
+            "Response socket {} failed, retrying in {:?}: {}",
+            self.path.display(),
+            backoff,
+            e
+        );
+        self.retry_at = Instant::now() + backoff;
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
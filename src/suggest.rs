@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{EntryType, FileEntry, Suggestion};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Shortest token worth suggesting, in characters.
+const MIN_TOKEN_CHARS: usize = 2;
+
+/// How many indexed entries contain each token: the alphanumeric words of
+/// file names and the whole names of directories, lowercased. Built with
+/// each full scan and kept up to date as the watcher adds and removes
+/// entries.
+#[derive(Debug, Default)]
+pub struct TokenTable {
+    counts: BTreeMap<String, usize>,
+}
+
+impl TokenTable {
+    pub fn build(files: &[FileEntry]) -> Self {
+        let counts = files
+            .par_iter()
+            .fold(HashMap::new, |mut counts: HashMap<String, usize>, file| {
+                for token in tokens(file) {
+                    *counts.entry(token).or_default() += 1;
+                }
+                counts
+            })
+            .reduce(HashMap::new, |mut counts, other| {
+                for (token, count) in other {
+                    *counts.entry(token).or_default() += count;
+                }
+                counts
+            });
+        Self {
+            counts: counts.into_iter().collect(),
+        }
+    }
+
+    pub fn add(&mut self, file: &FileEntry) {
+        for token in tokens(file) {
+            *self.counts.entry(token).or_default() += 1;
+        }
+    }
+
+    pub fn remove(&mut self, file: &FileEntry) {
+        for token in tokens(file) {
+            if let Some(count) = self.counts.get_mut(&token) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Completions of the last word of `query`, most frequent first. A
+    /// query that is empty or ends in whitespace is completed with the
+    /// most frequent tokens overall.
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        let last_word = query.rsplit(char::is_whitespace).next().unwrap_or("");
+        let kept = &query[..query.len() - last_word.len()];
+        let partial = last_word.to_lowercase();
+
+        let mut candidates: Vec<(&String, usize)> = self
+            .counts
+            .range(partial.clone()..)
+            .take_while(|(token, _)| token.starts_with(&partial))
+            .filter(|(token, _)| **token != partial)
+            .map(|(token, &count)| (token, count))
+            .collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(token, count)| Suggestion {
+                completion: format!("{}{}", kept, token),
+                token: token.clone(),
+                count,
+            })
+            .collect()
+    }
+}
+
+/// The distinct tokens of one entry's `display_path`.
+fn tokens(file: &FileEntry) -> Vec<String> {
+    let mut components: Vec<&str> = file
+        .display_path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != "~")
+        .collect();
+    let name = match file.entry_type {
+        EntryType::Dir => None,
+        EntryType::File | EntryType::Symlink => components.pop(),
+    };
+
+    let mut tokens: Vec<String> = components
+        .into_iter()
+        .map(|dir| dir.trim_start_matches('.').to_lowercase())
+        .collect();
+    if let Some(name) = name {
+        tokens.extend(
+            name.split(|c: char| !c.is_alphanumeric())
+                .map(str::to_lowercase),
+        );
+    }
+    tokens.retain(|token| token.chars().count() >= MIN_TOKEN_CHARS);
+    tokens.sort_unstable();
+    tokens.dedup();
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileMetadata;
+
+    fn table(display_paths: &[&str]) -> TokenTable {
+        let files: Vec<FileEntry> = display_paths
+            .iter()
+            .map(|path| FileEntry {
+                id: crate::file_id(path),
+                path: path.to_string(),
+                display_path: path.to_string(),
+                entry_type: EntryType::File,
+                metadata: FileMetadata::default(),
+            })
+            .collect();
+        TokenTable::build(&files)
+    }
+
+    fn suggested(table: &TokenTable, query: &str, limit: usize) -> Vec<(String, usize)> {
+        table
+            .suggest(query, limit)
+            .into_iter()
+            .map(|suggestion| (suggestion.completion, suggestion.count))
+            .collect()
+    }
+
+    #[test]
+    fn suggestions_are_ordered_by_count_then_token() {
+        let table = table(&[
+            "~/src/main.rs",
+            "~/src/mailer.rs",
+            "~/docs/mailer.md",
+            "~/docs/makefile",
+            "~/lib/main.c",
+            "~/lib/mask.c",
+        ]);
+        assert_eq!(
+            suggested(&table, "src ma", 10),
+            [
+                ("src mailer".to_string(), 2),
+                ("src main".to_string(), 2),
+                ("src makefile".to_string(), 1),
+                ("src mask".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            suggested(&table, "MA", 2),
+            [("mailer".to_string(), 2), ("main".to_string(), 2)]
+        );
+    }
+}
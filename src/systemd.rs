@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use anyhow::{Context, Result};
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixListener;
+use tracing::{info, warn};
+
+/// First file descriptor passed by the service manager (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+
+/// Returns the request socket systemd opened for us when the daemon was
+/// socket-activated, following the `sd_listen_fds(3)` protocol.
+pub fn inherited_listener() -> Result<Option<UnixListener>> {
+    let Some(pid) = std::env::var_os("LISTEN_PID") else {
+        return Ok(None);
+    };
+    // The variables may have been inherited from a socket-activated parent.
+    if pid.to_str().and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds: u32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse().ok())
+        .unwrap_or(0);
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, using only the first", fds);
+    }
+
+    // SAFETY: systemd hands the first listening socket over as fd 3 and
+    // nothing else in the process owns it.
+    let inherited = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    // The inherited descriptor is not close-on-exec, so it would leak into
+    // every `fd` child; the duplicate is, and dropping the original closes it.
+    let listener = inherited
+        .try_clone()
+        .context("Inherited file descriptor 3 is not a socket")?;
+    drop(inherited);
+    let addr = listener
+        .local_addr()
+        .context("Inherited file descriptor 3 is not a Unix socket")?;
+    listener.set_nonblocking(true)?;
+    match addr.as_pathname() {
+        Some(path) => info!("Using socket {} passed by systemd", path.display()),
+        None => info!("Using socket passed by systemd"),
+    }
+    Ok(Some(listener))
+}
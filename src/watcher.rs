@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use qs_daemon::{FileIndex, FsEvent};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{RwLock, mpsc};
+use tokio::time::{Duration, Instant, timeout};
+use tracing::{debug, info, warn};
+
+/// Quiet period after the last event before a batch is applied.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Upper bound on how long a continuous burst can delay a batch.
+const MAX_BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// Watches every configured root and feeds debounced batches of events
+/// into the index, taking the lock once per batch.
+pub async fn watch_roots(file_index: Arc<RwLock<FileIndex>>) {
+    let roots = file_index.read().await.roots().to_vec();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    // Hidden paths still come through; the index decides whether to keep
+    // them.
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<Event>| match res
+    {
+        Ok(event) => {
+            for fs_event in translate(event) {
+                let _ = tx.send(fs_event);
+            }
+        }
+        Err(e) => warn!("File watcher error: {}", e),
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Failed to start file watcher: {}", e);
+            return;
+        }
+    };
+
+    for root in &roots {
+        match watcher.watch(root, RecursiveMode::Recursive) {
+            Ok(()) => info!("Watching {} for changes", root.display()),
+            Err(e) => warn!("Failed to watch {}: {}", root.display(), e),
+        }
+    }
+
+    while let Some(first) = rx.recv().await {
+        let mut pending: HashMap<PathBuf, FsEvent> = HashMap::new();
+        pending.insert(fs_event_path(&first).to_path_buf(), first);
+
+        let deadline = Instant::now() + MAX_BATCH_DELAY;
+        loop {
+            let wait = DEBOUNCE.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait, rx.recv()).await {
+                Ok(Some(event)) => {
+                    pending.insert(fs_event_path(&event).to_path_buf(), event);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let count = pending.len();
+        file_index
+            .write()
+            .await
+            .apply_fs_events(pending.into_values());
+        debug!("Applied {} file system events", count);
+    }
+}
+
+fn translate(event: Event) -> Vec<FsEvent> {
+    let mut paths = event.paths.into_iter();
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.map(FsEvent::Created).collect()
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            paths.map(FsEvent::Removed).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            match (paths.next(), paths.next()) {
+                (Some(from), Some(to)) => vec![FsEvent::Removed(from), FsEvent::Created(to)],
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn fs_event_path(event: &FsEvent) -> &Path {
+    match event {
+        FsEvent::Created(path) | FsEvent::Removed(path) => path,
+    }
+}